@@ -0,0 +1,277 @@
+use crate::error::{Error, ErrorCode, ERR_BACKEND};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::{event, Level};
+
+/// Storage behind a partition's cache, such as a database. Partitions load keys they miss
+/// from it and pass writes on to it. Keys are scoped by namespace, like in the store.
+pub trait Backend: Send {
+    fn load(&mut self, namespace: &str, key: &str) -> Result<Option<String>, Error>;
+    fn save(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), Error>;
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<(), Error>;
+}
+
+fn backend_error(e: impl fmt::Display) -> Error {
+    Error {
+        code: ErrorCode::Backend,
+        msg: format!("{}: {}", ERR_BACKEND, e),
+    }
+}
+
+fn hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Keeps every key in a file of its own, `<dir>/<namespace>/<key>` with both names
+/// hex-encoded, so keys may contain any character.
+pub struct FileBackend {
+    dir: PathBuf,
+}
+
+impl FileBackend {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir).map_err(backend_error)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, namespace: &str, key: &str) -> PathBuf {
+        self.dir.join(hex(namespace)).join(hex(key))
+    }
+}
+
+impl Backend for FileBackend {
+    fn load(&mut self, namespace: &str, key: &str) -> Result<Option<String>, Error> {
+        match fs::read_to_string(self.path(namespace, key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(backend_error(e)),
+        }
+    }
+
+    fn save(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), Error> {
+        let path = self.path(namespace, key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(backend_error)?;
+        }
+        // write a temporary file first so readers never see half a value
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, value).map_err(backend_error)?;
+        fs::rename(&tmp, &path).map_err(backend_error)
+    }
+
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(namespace, key)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(backend_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Keeps keys in a local SQLite database, in a table `entries(namespace, key, value)`.
+pub struct SqliteBackend {
+    conn: Connection,
+}
+
+impl SqliteBackend {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let conn = Connection::open(path).map_err(backend_error)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS entries (
+                namespace TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (namespace, key)
+            )",
+        )
+        .map_err(backend_error)?;
+        Ok(Self { conn })
+    }
+}
+
+impl Backend for SqliteBackend {
+    fn load(&mut self, namespace: &str, key: &str) -> Result<Option<String>, Error> {
+        self.conn
+            .query_row(
+                "SELECT value FROM entries WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(backend_error)
+    }
+
+    fn save(&mut self, namespace: &str, key: &str, value: &str) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "INSERT INTO entries (namespace, key, value) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (namespace, key) DO UPDATE SET value = excluded.value",
+                params![namespace, key, value],
+            )
+            .map(|_| ())
+            .map_err(backend_error)
+    }
+
+    fn remove(&mut self, namespace: &str, key: &str) -> Result<(), Error> {
+        self.conn
+            .execute(
+                "DELETE FROM entries WHERE namespace = ?1 AND key = ?2",
+                params![namespace, key],
+            )
+            .map(|_| ())
+            .map_err(backend_error)
+    }
+}
+
+/// When writes reach the backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Before the client is answered, so a failed write is reported to it.
+    Through,
+    /// From a background thread every interval, saving only the last write to each key.
+    /// Writes still pending when the partition stops are lost.
+    Behind(Duration),
+}
+
+/// Writes not yet saved, by namespace and key, with `None` for a removal.
+type PendingWrites = HashMap<(String, String), Option<String>>;
+
+/// A backend shared between a partition's request loop and, with write-behind, the thread
+/// saving its writes.
+#[derive(Clone)]
+pub struct BackendHandle {
+    backend: Arc<Mutex<Box<dyn Backend>>>,
+    policy: WritePolicy,
+    pending: Arc<Mutex<PendingWrites>>,
+}
+
+impl BackendHandle {
+    pub fn new(backend: Box<dyn Backend>, policy: WritePolicy) -> Self {
+        let handle = Self {
+            backend: Arc::new(Mutex::new(backend)),
+            policy,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        };
+        if let WritePolicy::Behind(interval) = policy {
+            let (backend, pending) = (handle.backend.clone(), handle.pending.clone());
+            thread::spawn(move || loop {
+                thread::sleep(interval);
+                flush(&backend, &pending);
+            });
+        }
+        handle
+    }
+
+    pub fn policy(&self) -> WritePolicy {
+        self.policy
+    }
+
+    /// Loads `key`, seeing writes that are still pending.
+    pub fn load(&self, namespace: &str, key: &str) -> Result<Option<String>, Error> {
+        let id = (namespace.to_string(), key.to_string());
+        if let Some(write) = self.pending.lock().unwrap().get(&id) {
+            return Ok(write.clone());
+        }
+        self.backend.lock().unwrap().load(namespace, key)
+    }
+
+    /// Saves `value` under `key`, or removes the key for `None`.
+    pub fn write(&self, namespace: &str, key: &str, value: Option<&str>) -> Result<(), Error> {
+        match self.policy {
+            WritePolicy::Through => {
+                let mut backend = self.backend.lock().unwrap();
+                match value {
+                    Some(value) => backend.save(namespace, key, value),
+                    None => backend.remove(namespace, key),
+                }
+            }
+            WritePolicy::Behind(_) => {
+                let id = (namespace.to_string(), key.to_string());
+                let value = value.map(str::to_string);
+                self.pending.lock().unwrap().insert(id, value);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Saves the pending writes. The backend stays locked until they are all saved, so a load
+/// that no longer finds a write pending finds it saved.
+fn flush(backend: &Mutex<Box<dyn Backend>>, pending: &Mutex<PendingWrites>) {
+    let mut backend = backend.lock().unwrap();
+    let writes = std::mem::take(&mut *pending.lock().unwrap());
+    for ((namespace, key), value) in writes {
+        let saved = match &value {
+            Some(value) => backend.save(&namespace, &key, value),
+            None => backend.remove(&namespace, &key),
+        };
+        if let Err(e) = saved {
+            event!(Level::ERROR, "Failed to save {}: {}", key, e);
+            // retry on the next flush, unless the key has been written again since
+            pending
+                .lock()
+                .unwrap()
+                .entry((namespace, key))
+                .or_insert(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(backend: &mut dyn Backend) {
+        assert_eq!(backend.load("default", "user/1").unwrap(), None);
+        backend.save("default", "user/1", "ada").unwrap();
+        backend.save("default", "user/1", "grace").unwrap();
+        backend.save("billing", "user/1", "invoice").unwrap();
+        assert_eq!(
+            backend.load("default", "user/1").unwrap().as_deref(),
+            Some("grace")
+        );
+        backend.remove("default", "user/1").unwrap();
+        backend.remove("default", "user/1").unwrap();
+        assert_eq!(backend.load("default", "user/1").unwrap(), None);
+        assert_eq!(
+            backend.load("billing", "user/1").unwrap().as_deref(),
+            Some("invoice")
+        );
+    }
+
+    #[test]
+    fn backends_save_load_and_remove() {
+        let dir = std::env::temp_dir().join(format!("hitormiss-{}", uuid::Uuid::new_v4()));
+        round_trip(&mut FileBackend::new(&dir).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+        round_trip(&mut SqliteBackend::open(":memory:").unwrap());
+    }
+
+    #[test]
+    fn write_behind_loads_pending_writes() {
+        let backend = SqliteBackend::open(":memory:").unwrap();
+        let handle = BackendHandle::new(Box::new(backend), WritePolicy::Behind(Duration::MAX));
+        handle.write("default", "k", Some("v")).unwrap();
+        assert_eq!(handle.load("default", "k").unwrap().as_deref(), Some("v"));
+        assert_eq!(
+            handle.backend.lock().unwrap().load("default", "k").unwrap(),
+            None
+        );
+
+        flush(&handle.backend, &handle.pending);
+        handle.write("default", "k", None).unwrap();
+        assert_eq!(handle.load("default", "k").unwrap(), None);
+        flush(&handle.backend, &handle.pending);
+        assert_eq!(
+            handle.backend.lock().unwrap().load("default", "k").unwrap(),
+            None
+        );
+    }
+}
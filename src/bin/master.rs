@@ -1,20 +1,35 @@
 use chrono::{DateTime, Utc};
 use core::panic;
-use hash_ring::HashRing;
 use hitormiss::error::{Error, ErrorCode};
+use hitormiss::glob::glob_match;
 use hitormiss::parser::{
-    build_ack_response, build_error_response, build_lsp_response, build_miss_response,
-    build_ok_response, parse_request, CommandType, ParsedRequest,
+    build_ack_response, build_aggregate_response, build_best_effort_miss_response,
+    build_best_effort_ok_response, build_error_response, build_event_response, build_exec_request,
+    build_exec_response, build_lsp_response, build_message_response, build_namespaced_request,
+    build_notify_ack_response, build_ok_response, build_pattern_message_response,
+    build_pfdump_request, build_pfload_request, build_queued_response, build_scan_request,
+    build_scan_response, build_stats_response, build_subscription_response, build_tagged_frame,
+    build_value_response, build_watch_request, parse_array_response, parse_request,
+    parse_scan_args, parse_scan_response, parse_stats_response, parse_value_response, split_tag,
+    CommandType, ExecBatch, ParsedRequest,
 };
-use std::collections::HashSet;
+use hitormiss::placement::{
+    bounded_choice, compare, hash_tag, Placement, PlacementKind, MAX_VIRTUAL_NODES, MAX_WEIGHT,
+};
+use hitormiss::sketch::HyperLogLog;
+use hitormiss::store::{KeyEvent, DEFAULT_NAMESPACE};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpListener;
 use tokio::net::TcpStream;
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinSet;
 use tracing::{event, Level};
 use uuid::Uuid;
 
@@ -22,19 +37,106 @@ use uuid::Uuid;
 struct Partition {
     id: Uuid,
     addr: String,
-    conn: Arc<Mutex<TcpStream>>,
+    link: Arc<PartitionLink>,
     time_joined: SystemTime,
+    weight: u32,
+    /// Requests forwarded to this partition that have not been answered yet.
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Counts a request as in flight on a partition until dropped.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Partition {
-    fn new(addr: String, conn: Arc<Mutex<TcpStream>>) -> Self {
+    fn new(addr: String, link: Arc<PartitionLink>, weight: u32) -> Self {
         Self {
             id: Uuid::new_v4(),
             addr,
-            conn,
+            link,
             time_joined: SystemTime::now(),
+            weight,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn load(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    fn track_request(&self) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.in_flight.clone())
+    }
+}
+
+/// Responses the master is waiting for on a partition's connection, by request id. `None`
+/// once the connection is gone.
+type Waiting = Arc<std::sync::Mutex<Option<HashMap<u64, oneshot::Sender<Vec<u8>>>>>>;
+
+/// The master's end of the connection to a partition. Requests are tagged with an id that
+/// comes back with their response, so any number can be in flight at once and a partition
+/// may answer a quick request before a slow one sent earlier.
+struct PartitionLink {
+    writer: Mutex<OwnedWriteHalf>,
+    waiting: Waiting,
+    next_id: AtomicU64,
+}
+
+impl PartitionLink {
+    fn open(socket: TcpStream, addr: String) -> Self {
+        let (reader, writer) = socket.into_split();
+        let waiting: Waiting = Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+        tokio::spawn(dispatch_responses(reader, waiting.clone(), addr));
+        Self {
+            writer: Mutex::new(writer),
+            waiting,
+            next_id: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Hands each response read from a partition to the request waiting for it. When the
+/// connection goes, every request still waiting fails.
+async fn dispatch_responses(mut reader: OwnedReadHalf, waiting: Waiting, addr: String) {
+    let mut pending = Vec::new();
+    let mut buf = vec![0; 4096];
+    loop {
+        while let Some(end) = pending.iter().position(|&c| c == b'\0') {
+            let frame: Vec<u8> = pending.drain(..=end).collect();
+            let Some((id, response)) = split_tag(&frame) else {
+                event!(Level::ERROR, "Untagged response from partition: {:?}", addr);
+                continue;
+            };
+            let sender = waiting.lock().unwrap().as_mut().and_then(|w| w.remove(&id));
+            if let Some(sender) = sender {
+                // the request may have been given up on, in which case nobody listens
+                let _ = sender.send(response.to_vec());
+            }
+        }
+        match reader.read(&mut buf).await {
+            Ok(0) => {
+                event!(Level::ERROR, "Zero bytes read from partition: {:?}", addr);
+                break;
+            }
+            Ok(amount) => pending.extend_from_slice(&buf[..amount]),
+            Err(error) => {
+                event!(
+                    Level::ERROR,
+                    "Failed to read from partition: {:?}, error: {:?}",
+                    addr,
+                    error
+                );
+                break;
+            }
         }
     }
+    waiting.lock().unwrap().take();
 }
 
 impl fmt::Debug for Partition {
@@ -45,6 +147,8 @@ impl fmt::Debug for Partition {
             .field("id", &self.id)
             .field("addr", &self.addr)
             .field("time_joined", &timestamp_formatted.to_string())
+            .field("weight", &self.weight)
+            .field("in_flight", &self.load())
             .finish()
     }
 }
@@ -69,15 +173,167 @@ impl fmt::Display for Partition {
     }
 }
 
-type Ring = Arc<Mutex<HashRing<Partition>>>;
+type Ring = Arc<Mutex<Box<dyn Placement<Partition>>>>;
 type PartitionSet = Arc<Mutex<HashSet<Partition>>>;
 
+/// A client connection kept open after `SUBSCRIBE`, `PSUBSCRIBE` or `KSUBSCRIBE`. Published messages are
+/// written to `conn` while the connection's own task keeps reading (un)subscribe requests.
+struct Subscriber {
+    conn: Arc<Mutex<OwnedWriteHalf>>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    /// Key patterns watched with `KSUBSCRIBE`, by namespace and pattern, each with the event
+    /// types it wants.
+    key_patterns: HashMap<(Option<String>, String), HashSet<KeyEvent>>,
+}
+
+impl Subscriber {
+    fn new(conn: Arc<Mutex<OwnedWriteHalf>>) -> Self {
+        Self {
+            conn,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            key_patterns: HashMap::new(),
+        }
+    }
+
+    fn subscription_count(&self) -> usize {
+        self.channels.len() + self.patterns.len() + self.key_patterns.len()
+    }
+
+    /// Whether any key pattern watched in `namespace` wants `event` on `key`.
+    fn watches(&self, event: KeyEvent, namespace: Option<&str>, key: &str) -> bool {
+        self.key_patterns
+            .iter()
+            .any(|((watched, pattern), events)| {
+                watched.as_deref() == namespace
+                    && events.contains(&event)
+                    && glob_match(pattern, key)
+            })
+    }
+}
+
+type Subscribers = Arc<Mutex<HashMap<Uuid, Subscriber>>>;
+
+/// Tokens handed to partitions as they register. Opening an event stream takes one, so that
+/// clients can't pose as partitions and send keyspace events of their own.
+type EventTokens = Arc<std::sync::Mutex<HashSet<Uuid>>>;
+
+/// State of a client connection between `WATCH` or `MULTI` and `EXEC` or `DISCARD`.
+#[derive(Debug, Default)]
+struct Transaction {
+    /// Keys passed to `WATCH`, with the version each was at when watched.
+    watches: Vec<(String, u64)>,
+    /// Requests queued since `MULTI`, or `None` before `MULTI`.
+    queued: Option<Vec<ParsedRequest>>,
+    /// Namespace of the request that started the transaction, which every later one must share.
+    namespace: Option<String>,
+}
+
+impl Transaction {
+    fn is_open(&self) -> bool {
+        self.queued.is_some() || !self.watches.is_empty()
+    }
+}
+
+/// How the master answers a client when the owning partition cannot be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FailureMode {
+    /// Reply with a `PartitionUnavailable` error.
+    Strict,
+    /// Treat the cluster as a cache: acknowledge writes with `OK BEST_EFFORT` and report
+    /// reads as misses with `BMS <key>`.
+    BestEffort,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    failure_mode: FailureMode,
+    placement: PlacementKind,
+    /// Virtual nodes per unit of partition weight, used by the `ring` placement.
+    virtual_nodes: u32,
+    /// Print a comparison of all placement strategies and exit instead of serving.
+    placement_report: bool,
+    /// Enables consistent hashing with bounded loads: a partition with more than this many
+    /// times the average in-flight requests hands keys on to the next candidate.
+    load_factor: Option<f64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            failure_mode: FailureMode::Strict,
+            placement: PlacementKind::Ring,
+            virtual_nodes: 1,
+            placement_report: false,
+            load_factor: None,
+        }
+    }
+}
+
+impl Config {
+    fn from_args() -> Self {
+        let mut config = Config::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--best-effort" => config.failure_mode = FailureMode::BestEffort,
+                "--placement" => match args.next().and_then(|p| p.parse().ok()) {
+                    Some(placement) => config.placement = placement,
+                    None => panic!("--placement expects one of: ring, rendezvous, jump, maglev"),
+                },
+                "--vnodes" => match args.next().and_then(|v| v.parse::<u32>().ok()) {
+                    Some(vnodes) if (1..=MAX_VIRTUAL_NODES).contains(&vnodes) => {
+                        config.virtual_nodes = vnodes
+                    }
+                    _ => panic!(
+                        "--vnodes expects an integer from 1 to {}",
+                        MAX_VIRTUAL_NODES
+                    ),
+                },
+                "--placement-report" => config.placement_report = true,
+                "--bounded-loads" => match args.next().and_then(|c| c.parse::<f64>().ok()) {
+                    Some(factor) if factor >= 1.0 => config.load_factor = Some(factor),
+                    _ => panic!("--bounded-loads expects a load factor of at least 1.0"),
+                },
+                _ => event!(Level::WARN, "Ignoring unknown argument: {}", arg),
+            }
+        }
+        config
+    }
+}
+
+fn print_placement_report(virtual_nodes: u32) {
+    const NUM_PARTITIONS: usize = 10;
+    const NUM_KEYS: usize = 100_000;
+    println!(
+        "Placing {} keys on {} partitions ({} virtual nodes per ring partition)",
+        NUM_KEYS, NUM_PARTITIONS, virtual_nodes
+    );
+    println!(
+        "Ideal: max/mean 1.000, moved on add {:.3}, moved on remove {:.3}",
+        1.0 / (NUM_PARTITIONS + 1) as f64,
+        1.0 / NUM_PARTITIONS as f64
+    );
+    for stats in compare(NUM_PARTITIONS, NUM_KEYS, virtual_nodes) {
+        println!("{}", stats);
+    }
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    let config = Arc::new(Config::from_args());
+    event!(Level::INFO, "Master configuration: {:?}", config);
+
+    if config.placement_report {
+        print_placement_report(config.virtual_nodes);
+        return;
+    }
+
     const MASTER_ADDR: &str = "127.0.0.1:6969";
     event!(
         Level::INFO,
@@ -94,13 +350,11 @@ async fn main() {
         }
     };
 
-    // # of replicas per partition
-    let num_replicas = 1;
-    // set this to define some initial nodes
-    let initial_nodes: Vec<Partition> = Vec::new();
-    // consistent hashing node ring
-    let ring: Ring = Arc::new(Mutex::new(HashRing::new(initial_nodes, num_replicas)));
+    // key placement across partitions, consistent hashing ring by default
+    let ring: Ring = Arc::new(Mutex::new(config.placement.build(config.virtual_nodes)));
     let partition_set: PartitionSet = Arc::new(Mutex::new(HashSet::new()));
+    let subscribers: Subscribers = Arc::new(Mutex::new(HashMap::new()));
+    let event_tokens: EventTokens = Arc::default();
 
     loop {
         let (socket, _addr) = match listener.accept().await {
@@ -120,9 +374,21 @@ async fn main() {
 
         let ring_clone: Ring = ring.clone();
         let partition_set_clone: PartitionSet = partition_set.clone();
+        let subscribers_clone: Subscribers = subscribers.clone();
+        let event_tokens_clone: EventTokens = event_tokens.clone();
+        let config_clone = config.clone();
 
         tokio::spawn(async move {
-            match handle_connection(socket, ring_clone, partition_set_clone).await {
+            match handle_connection(
+                socket,
+                ring_clone,
+                partition_set_clone,
+                subscribers_clone,
+                event_tokens_clone,
+                config_clone,
+            )
+            .await
+            {
                 Ok(_) => {}
                 Err(e) => {
                     event!(Level::DEBUG, "Failed to handle connection: {}", e);
@@ -136,6 +402,9 @@ async fn handle_connection(
     mut socket: TcpStream,
     ring: Ring,
     partition_set: PartitionSet,
+    subscribers: Subscribers,
+    event_tokens: EventTokens,
+    config: Arc<Config>,
 ) -> Result<(), Error> {
     event!(
         Level::DEBUG,
@@ -143,74 +412,160 @@ async fn handle_connection(
         format!("Handling connection: {:?}", socket)
     );
 
-    let mut buf = [0; 4096];
-    match socket.try_read(&mut buf) {
-        Ok(_) => match parse_request(buf.to_vec()) {
-            Ok(parsed_request) => {
-                event!(Level::DEBUG, "Parsed request: {:?}", parsed_request);
-                match parsed_request.cmd {
-                    CommandType::Get
-                    | CommandType::Set
-                    | CommandType::Delete
-                    | CommandType::Lsd => {
-                        forward_to_partition(socket, &parsed_request, ring, partition_set).await;
-                        Ok(())
+    // `SELECT` keeps the connection open and sets the namespace of the requests that follow
+    let mut session = false;
+    let mut selected: Option<String> = None;
+    loop {
+        let mut buf = [0; 4096];
+        let mut parsed_request = match socket.read(&mut buf).await {
+            Ok(0) if session => return Ok(()),
+            Ok(_) => match parse_request(buf.to_vec()) {
+                Ok(parsed_request) => parsed_request,
+                Err(e) => {
+                    socket.write_all(&build_error_response(&e)).await.unwrap();
+                    if session {
+                        continue;
                     }
-                    CommandType::Notify => {
-                        handle_notify(socket, ring, partition_set).await;
-                        Ok(())
+                    return Err(e);
+                }
+            },
+            Err(e) => {
+                event!(
+                    Level::ERROR,
+                    "Failed to read from socket: {:?} {:?}",
+                    socket,
+                    e
+                );
+                return Err(Error::from_code(ErrorCode::FailedSocketRead));
+            }
+        };
+        select_namespace(&mut parsed_request, selected.as_deref());
+        event!(Level::DEBUG, "Parsed request: {:?}", parsed_request);
+        match parsed_request.cmd {
+            CommandType::Select => {
+                let response = match parsed_request.args.as_slice() {
+                    [name] => {
+                        session = true;
+                        selected = Some(name.clone()).filter(|name| name != DEFAULT_NAMESPACE);
+                        build_ok_response()
                     }
-                    CommandType::ListPartitions => {
-                        handle_list(socket, partition_set).await;
+                    [] => build_error_response(&Error::from_code(ErrorCode::NotEnoughArgs)),
+                    _ => build_error_response(&Error::from_code(ErrorCode::InvalidRequestArg)),
+                };
+                socket.write_all(&response).await.unwrap();
+            }
+            CommandType::PfCount | CommandType::PfMerge => {
+                handle_pf_union(&mut socket, &parsed_request, &ring, &partition_set, &config).await;
+            }
+            ref cmd if cmd.has_key() => {
+                forward_to_partition(
+                    &mut socket,
+                    &parsed_request,
+                    ring.clone(),
+                    partition_set.clone(),
+                    &config,
+                )
+                .await;
+            }
+            CommandType::Notify => {
+                return match parse_weight(&parsed_request) {
+                    Ok(weight) => {
+                        handle_notify(socket, ring, partition_set, &event_tokens, weight).await;
                         Ok(())
                     }
-                    _ => {
-                        socket
-                            .write_all(&build_error_response(&Error::from_code(
-                                ErrorCode::UnsupportedCommandMaster,
-                            )))
-                            .await
-                            .unwrap();
-                        Ok(())
+                    Err(e) => {
+                        socket.write_all(&build_error_response(&e)).await.unwrap();
+                        Err(e)
                     }
                 }
             }
-            Err(e) => {
-                socket.write_all(&build_error_response(&e)).await.unwrap();
-                Err(e)
+            CommandType::Stats => {
+                handle_stats(&mut socket, &parsed_request, &ring, &partition_set).await;
+            }
+            ref cmd if cmd.is_broadcast() => {
+                handle_broadcast(&mut socket, &parsed_request, &ring, &partition_set).await;
+            }
+            CommandType::Scan => {
+                handle_scan(&mut socket, &parsed_request, &ring, &partition_set).await;
+            }
+            CommandType::ListPartitions => {
+                handle_list(&mut socket, &partition_set).await;
+            }
+            CommandType::Subscribe
+            | CommandType::Unsubscribe
+            | CommandType::PSubscribe
+            | CommandType::PUnsubscribe
+            | CommandType::KSubscribe
+            | CommandType::KUnsubscribe => {
+                handle_subscriber(socket, parsed_request, selected, subscribers).await;
+                return Ok(());
+            }
+            CommandType::Publish => {
+                handle_publish(&mut socket, &parsed_request, &subscribers).await;
+            }
+            CommandType::Multi
+            | CommandType::Exec
+            | CommandType::Discard
+            | CommandType::Watch
+            | CommandType::Unwatch => {
+                handle_transaction(
+                    &mut socket,
+                    parsed_request,
+                    selected.as_deref(),
+                    &ring,
+                    &partition_set,
+                    &config,
+                )
+                .await;
+            }
+            CommandType::Event if is_event_token(&parsed_request.args, &event_tokens) => {
+                handle_event_stream(socket, subscribers).await;
+                return Ok(());
+            }
+            _ => {
+                socket
+                    .write_all(&build_error_response(&Error::from_code(
+                        ErrorCode::UnsupportedCommandMaster,
+                    )))
+                    .await
+                    .unwrap();
             }
-        },
-        Err(e) => {
-            event!(
-                Level::ERROR,
-                "Failed to read from socket: {:?} {:?}",
-                socket,
-                e
-            );
-            Err(Error::from_code(ErrorCode::FailedSocketRead))
+        }
+        if !session {
+            return Ok(());
         }
     }
 }
 
-async fn handle_failed_forward(mut client_socket: TcpStream, request: &ParsedRequest) {
-    match request.cmd {
-        CommandType::Get => {
-            if let Some(key) = &request.key {
-                client_socket
-                    .write_all(&build_miss_response(key.as_str()))
-                    .await
-                    .unwrap();
-            } else {
-                client_socket
-                    .write_all(&build_error_response(&Error::from_code(ErrorCode::Unknown)))
-                    .await
-                    .unwrap();
-            }
+/// Puts a request without an `@<namespace>` prefix in the namespace its connection selected.
+/// The default namespace is kept as `None` either way, so namespaces compare equal.
+fn select_namespace(request: &mut ParsedRequest, selected: Option<&str>) {
+    match request.namespace.as_deref() {
+        None => request.namespace = selected.map(str::to_string),
+        Some(DEFAULT_NAMESPACE) => request.namespace = None,
+        Some(_) => {}
+    }
+}
+
+async fn handle_failed_forward(
+    client_socket: &mut TcpStream,
+    request: &ParsedRequest,
+    failure_mode: FailureMode,
+) {
+    let response = failed_forward_response(request, failure_mode);
+    client_socket.write_all(&response).await.unwrap();
+}
+
+/// What a client is told when the partition owning its request could not be reached.
+fn failed_forward_response(request: &ParsedRequest, failure_mode: FailureMode) -> Vec<u8> {
+    match (failure_mode, &request.cmd, &request.key) {
+        (FailureMode::BestEffort, CommandType::Get, Some(key)) => {
+            build_best_effort_miss_response(key.as_str())
         }
-        CommandType::Set | CommandType::Delete => {
-            client_socket.write_all(&build_ok_response()).await.unwrap();
+        (FailureMode::BestEffort, CommandType::Set | CommandType::Delete, _) => {
+            build_best_effort_ok_response()
         }
-        _ => {}
+        _ => build_error_response(&Error::from_code(ErrorCode::PartitionUnavailable)),
     }
 }
 
@@ -219,89 +574,882 @@ async fn unregister_partition(partition: &Partition, ring: Ring, partition_set:
     partition_set.lock().await.remove(partition);
 }
 
+/// Picks the partition that should serve `key`, honouring hash tags and, when enabled and
+/// `may_spill` is set, bounded loads.
+async fn resolve_partition(
+    key: &str,
+    may_spill: bool,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+    config: &Config,
+) -> Option<Partition> {
+    let key = hash_tag(key);
+    let ring = ring.lock().await;
+    match config.load_factor.filter(|_| may_spill) {
+        None => ring.get_node(key).cloned(),
+        Some(load_factor) => {
+            let candidates = ring.candidates(key);
+            let partitions = partition_set.lock().await;
+            let total_load = partitions.iter().map(Partition::load).sum();
+            bounded_choice(
+                &candidates,
+                Partition::load,
+                total_load,
+                partitions.len(),
+                load_factor,
+            )
+            .cloned()
+        }
+    }
+}
+
 async fn forward_to_partition(
-    mut client_socket: TcpStream,
+    client_socket: &mut TcpStream,
     request: &ParsedRequest,
     ring: Ring,
     partition_set: PartitionSet,
+    config: &Config,
 ) {
-    let responsible_partition: Option<Partition> = if let Some(key) = &request.key {
-        ring.lock()
+    let response = forward_request(request, &ring, &partition_set, config).await;
+    client_socket.write_all(&response).await.unwrap();
+}
+
+/// Sends a keyed request to the partition serving its key and returns the answer. A `GET`
+/// that bounded loads spilled to another partition is asked again of the owner when it
+/// misses there, since writes only ever reach the owner.
+async fn forward_request(
+    request: &ParsedRequest,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+    config: &Config,
+) -> Vec<u8> {
+    let no_partitions = || build_error_response(&Error::from_code(ErrorCode::NoPartitionsInRing));
+    let Some(key) = &request.key else {
+        return no_partitions();
+    };
+    let Some(owner) = resolve_partition(key, false, ring, partition_set, config).await else {
+        return no_partitions();
+    };
+    let partition = if request.cmd.may_spill() {
+        resolve_partition(key, true, ring, partition_set, config)
             .await
-            .get_node(key.to_string())
-            .map(Clone::clone)
+            .unwrap_or_else(|| owner.clone())
     } else {
-        None
+        owner.clone()
     };
-    match responsible_partition {
-        Some(partition) => {
-            let mut partition_socket = partition.conn.lock().await;
-            if let Err(e) = partition_socket
-                .write_all(request.original_rq.as_bytes())
-                .await
-            {
-                event!(
-                    Level::ERROR,
-                    "Failed to write to partition: {:?} {:?}",
-                    partition.addr,
-                    e
-                );
-                unregister_partition(&partition, ring, partition_set).await;
-                handle_failed_forward(client_socket, request).await;
+    let partition_request =
+        build_namespaced_request(request.namespace.as_deref(), request.original_rq.as_bytes());
+    let mut response = ask(&partition, &partition_request, ring, partition_set).await;
+    if partition != owner && response.as_ref().is_none_or(|r| r.starts_with(b"MSS")) {
+        response = ask(&owner, &partition_request, ring, partition_set).await;
+    }
+    response.unwrap_or_else(|| failed_forward_response(request, config.failure_mode))
+}
+
+/// `exchange` counting the request as in flight on `partition`, which is dropped from the
+/// ring if it can't be reached.
+async fn ask(
+    partition: &Partition,
+    request: &[u8],
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) -> Option<Vec<u8>> {
+    let _in_flight = partition.track_request();
+    match exchange(partition, request).await {
+        Ok(response) => Some(response),
+        Err(_) => {
+            unregister_partition(partition, ring.clone(), partition_set.clone()).await;
+            None
+        }
+    }
+}
+
+/// Sends `request` to `partition` and waits for its response. Requests and responses are
+/// `\0`-terminated, so partitions can tell where a large one ends, and tagged with an id so
+/// other requests to the same partition need not wait for this one.
+async fn exchange(partition: &Partition, request: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let link = &partition.link;
+    let id = link.next_id.fetch_add(1, Ordering::SeqCst);
+    let (sender, response) = oneshot::channel();
+    match link.waiting.lock().unwrap().as_mut() {
+        Some(waiting) => waiting.insert(id, sender),
+        None => return Err(std::io::ErrorKind::NotConnected.into()),
+    };
+    let framed = build_tagged_frame(id, request);
+    if let Err(e) = link.writer.lock().await.write_all(&framed).await {
+        event!(
+            Level::ERROR,
+            "Failed to write to partition: {:?} {:?}",
+            partition.addr,
+            e
+        );
+        if let Some(waiting) = link.waiting.lock().unwrap().as_mut() {
+            waiting.remove(&id);
+        }
+        return Err(e);
+    }
+    event!(
+        Level::DEBUG,
+        "Forwarded request to partition {:?}: {}",
+        partition.addr,
+        String::from_utf8_lossy(request)
+    );
+
+    let response = response
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+    event!(
+        Level::DEBUG,
+        "Got response from partition: {:?}: {}",
+        partition.addr,
+        String::from_utf8_lossy(&response)
+    );
+    Ok(response)
+}
+
+/// `PFCOUNT` and `PFMERGE`. Keys held by a single partition are left to it; otherwise the
+/// master fetches every source with `PFDUMP`, merges them itself and answers the count, or
+/// hands the union to the destination's partition with `PFLOAD`.
+async fn handle_pf_union(
+    socket: &mut TcpStream,
+    request: &ParsedRequest,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+    config: &Config,
+) {
+    let keys = request.keys();
+    let mut owners = Vec::with_capacity(keys.len());
+    for key in &keys {
+        match resolve_partition(key, false, ring, partition_set, config).await {
+            Some(partition) => owners.push(partition),
+            None => {
+                socket
+                    .write_all(&build_error_response(&Error::from_code(
+                        ErrorCode::NoPartitionsInRing,
+                    )))
+                    .await
+                    .unwrap();
                 return;
             }
-            event!(
-                Level::DEBUG,
-                "Forwarded request to partition {:?}: {}",
-                partition.addr,
-                request.original_rq
-            );
-            let mut buf = vec![0; 4096];
-            let read_amount = match partition_socket.read(&mut buf).await {
-                Ok(amount) => amount,
-                Err(error) => {
-                    event!(
-                        Level::ERROR,
-                        "Failed to read from partition: {:?}, error: {:?}",
-                        partition.addr,
-                        error
-                    );
-                    unregister_partition(&partition, ring, partition_set).await;
-                    handle_failed_forward(client_socket, request).await;
+        }
+    }
+    if owners.iter().all(|owner| *owner == owners[0]) {
+        forward_to_partition(socket, request, ring.clone(), partition_set.clone(), config).await;
+        return;
+    }
+
+    // PFMERGE keeps its destination out of the union, its partition merges it in
+    let sources = match request.cmd {
+        CommandType::PfMerge => 1,
+        _ => 0,
+    };
+    let namespace = request.namespace.as_deref();
+    let mut union = HyperLogLog::default();
+    for (key, partition) in keys.iter().zip(&owners).skip(sources) {
+        let _in_flight = partition.track_request();
+        let dump = build_namespaced_request(namespace, &build_pfdump_request(key));
+        let response = match exchange(partition, &dump).await {
+            Ok(response) => response,
+            Err(_) => {
+                unregister_partition(partition, ring.clone(), partition_set.clone()).await;
+                handle_failed_forward(socket, request, config.failure_mode).await;
+                return;
+            }
+        };
+        match parse_value_response(&response).map(HyperLogLog::from_hex) {
+            Some(Ok(hll)) => {
+                union.merge(&hll);
+            }
+            Some(Err(e)) => {
+                socket.write_all(&build_error_response(&e)).await.unwrap();
+                return;
+            }
+            // a missing key adds nothing, errors such as a wrong type go back to the client
+            None if response.starts_with(b"MSS") => {}
+            None => {
+                socket.write_all(&response).await.unwrap();
+                return;
+            }
+        }
+    }
+
+    let response = match request.cmd {
+        CommandType::PfCount => build_value_response(&union.count().to_string()),
+        _ => {
+            let destination = &owners[0];
+            let _in_flight = destination.track_request();
+            let load = build_pfload_request(keys[0], &union.to_hex());
+            match exchange(destination, &build_namespaced_request(namespace, &load)).await {
+                Ok(response) => response,
+                Err(_) => {
+                    unregister_partition(destination, ring.clone(), partition_set.clone()).await;
+                    handle_failed_forward(socket, request, config.failure_mode).await;
                     return;
                 }
-            };
+            }
+        }
+    };
+    socket.write_all(&response).await.unwrap();
+}
 
-            if read_amount == 0 {
-                event!(
-                    Level::ERROR,
-                    "Zero bytes read from partition: {:?}",
-                    partition.addr
-                );
-                unregister_partition(&partition, ring, partition_set).await;
-                handle_failed_forward(client_socket, request).await;
+/// Admin commands such as `DBSIZE` and `FLUSHALL`, sent to every partition at once. The
+/// answer sums the partitions' counts and lists each partition's own reply, with an error
+/// line for each partition that could not be reached.
+async fn handle_broadcast(
+    socket: &mut TcpStream,
+    request: &ParsedRequest,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) {
+    let replies = broadcast(request, ring, partition_set).await;
+    let total = replies
+        .iter()
+        .filter_map(|(_, reply)| parse_value_response(reply))
+        .filter_map(|count| count.parse::<u64>().ok())
+        .sum();
+    socket
+        .write_all(&build_aggregate_response(total, &replies))
+        .await
+        .unwrap();
+}
+
+/// `STATS`, answered with each counter summed over the partitions that could be reached,
+/// followed by every partition's own reply.
+async fn handle_stats(
+    socket: &mut TcpStream,
+    request: &ParsedRequest,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) {
+    let replies = broadcast(request, ring, partition_set).await;
+    let mut totals: Vec<(String, u64)> = Vec::new();
+    for stats in replies
+        .iter()
+        .filter_map(|(_, reply)| parse_stats_response(reply))
+    {
+        for (name, count) in stats {
+            match totals.iter_mut().find(|(total, _)| *total == name) {
+                Some((_, total)) => *total += count,
+                None => totals.push((name, count)),
+            }
+        }
+    }
+    socket
+        .write_all(&build_stats_response(&totals, &replies))
+        .await
+        .unwrap();
+}
+
+/// Sends `request` to every partition at once, returning each partition's reply by address,
+/// or an error for partitions that could not be reached.
+async fn broadcast(
+    request: &ParsedRequest,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) -> Vec<(String, Vec<u8>)> {
+    let partitions: Vec<Partition> = partition_set.lock().await.iter().cloned().collect();
+    let mut exchanges = JoinSet::new();
+    for partition in partitions {
+        let partition_request =
+            build_namespaced_request(request.namespace.as_deref(), request.original_rq.as_bytes());
+        exchanges.spawn(async move {
+            let _in_flight = partition.track_request();
+            let response = exchange(&partition, &partition_request).await;
+            (partition, response)
+        });
+    }
+
+    let mut replies = Vec::new();
+    while let Some(joined) = exchanges.join_next().await {
+        let Ok((partition, response)) = joined else {
+            continue;
+        };
+        let reply = match response {
+            Ok(reply) => reply,
+            Err(_) => {
+                unregister_partition(&partition, ring.clone(), partition_set.clone()).await;
+                build_error_response(&Error::from_code(ErrorCode::PartitionUnavailable))
+            }
+        };
+        replies.push((partition.addr, reply));
+    }
+    replies.sort();
+    replies
+}
+
+/// Cluster-wide `SCAN`. Partitions are visited one per call in address order, and the cursor
+/// `<partition addr>/<partition cursor>` records where to resume. A cursor of `0` starts a new
+/// scan and is returned once every partition has been exhausted.
+async fn handle_scan(
+    socket: &mut TcpStream,
+    request: &ParsedRequest,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) {
+    let scan = match parse_scan_args(&request.args) {
+        Ok(scan) => scan,
+        Err(e) => {
+            socket.write_all(&build_error_response(&e)).await.unwrap();
+            return;
+        }
+    };
+    let (resume_addr, position) = if scan.cursor == "0" {
+        (None, "0")
+    } else {
+        match scan.cursor.rsplit_once('/') {
+            Some((addr, position)) => (Some(addr), position),
+            None => {
+                socket
+                    .write_all(&build_error_response(&Error::from_code(
+                        ErrorCode::InvalidRequestArg,
+                    )))
+                    .await
+                    .unwrap();
                 return;
             }
-            event!(
-                Level::DEBUG,
-                "Got response from partition: {:?}: {}",
-                partition.addr,
-                String::from_utf8(buf[..read_amount].to_vec()).unwrap()
-            );
-            client_socket.write_all(&buf[..read_amount]).await.unwrap();
         }
-        None => {
-            client_socket
+    };
+
+    let mut partitions: Vec<Partition> = partition_set.lock().await.iter().cloned().collect();
+    partitions.sort_by(|a, b| a.addr.cmp(&b.addr));
+    // a partition that left mid-scan is skipped rather than failing the whole scan
+    let idx = match resume_addr {
+        None => 0,
+        Some(addr) => partitions
+            .iter()
+            .position(|p| p.addr.as_str() >= addr)
+            .unwrap_or(partitions.len()),
+    };
+    let Some(partition) = partitions.get(idx) else {
+        socket
+            .write_all(&build_scan_response("0", &[]))
+            .await
+            .unwrap();
+        return;
+    };
+    let position = if resume_addr == Some(partition.addr.as_str()) {
+        position
+    } else {
+        "0"
+    };
+
+    let _in_flight = partition.track_request();
+    let partition_request = build_namespaced_request(
+        request.namespace.as_deref(),
+        &build_scan_request(position, scan.pattern.as_deref(), scan.count),
+    );
+    let response = match exchange(partition, &partition_request).await {
+        Ok(response) => response,
+        Err(_) => {
+            unregister_partition(partition, ring.clone(), partition_set.clone()).await;
+            socket
                 .write_all(&build_error_response(&Error::from_code(
-                    ErrorCode::NoPartitionsInRing,
+                    ErrorCode::PartitionUnavailable,
                 )))
                 .await
                 .unwrap();
+            return;
+        }
+    };
+    let Some((next_position, keys)) = parse_scan_response(&response) else {
+        // pass partition errors such as an invalid cursor straight through
+        socket.write_all(&response).await.unwrap();
+        return;
+    };
+
+    let cursor = if next_position != "0" {
+        format!("{}/{}", partition.addr, next_position)
+    } else if let Some(next) = partitions.get(idx + 1) {
+        format!("{}/0", next.addr)
+    } else {
+        "0".to_string()
+    };
+    socket
+        .write_all(&build_scan_response(&cursor, &keys))
+        .await
+        .unwrap();
+}
+
+/// Serves a subscriber connection until it drops or holds no subscriptions any more. Only
+/// (un)subscribe requests are accepted on it; everything else is answered with an error.
+/// `selected` is the namespace chosen with `SELECT` for key patterns without a prefix.
+async fn handle_subscriber(
+    socket: TcpStream,
+    request: ParsedRequest,
+    selected: Option<String>,
+    subscribers: Subscribers,
+) {
+    let id = Uuid::new_v4();
+    let (mut reader, writer) = socket.into_split();
+    let conn = Arc::new(Mutex::new(writer));
+    subscribers
+        .lock()
+        .await
+        .insert(id, Subscriber::new(conn.clone()));
+    event!(Level::DEBUG, "Subscriber {} connected", id);
+
+    let mut next = Ok(request);
+    loop {
+        let response = match next {
+            Ok(request) => update_subscriptions(id, &request, &subscribers).await,
+            Err(e) => build_error_response(&e),
+        };
+        if conn.lock().await.write_all(&response).await.is_err() {
+            break;
+        }
+        let subscribed = subscribers
+            .lock()
+            .await
+            .get(&id)
+            .is_some_and(|subscriber| subscriber.subscription_count() > 0);
+        if !subscribed {
+            break;
+        }
+
+        let mut buf = [0; 4096];
+        next = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(amount) => parse_request(buf[..amount].to_vec()).map(|mut request| {
+                select_namespace(&mut request, selected.as_deref());
+                request
+            }),
+        };
+    }
+
+    subscribers.lock().await.remove(&id);
+    event!(Level::DEBUG, "Subscriber {} disconnected", id);
+}
+
+/// Applies one (un)subscribe request, answering with a `SUB` frame per channel or pattern.
+/// The unsubscribe commands drop every channel or pattern of their kind when given none.
+async fn update_subscriptions(
+    id: Uuid,
+    request: &ParsedRequest,
+    subscribers: &Subscribers,
+) -> Vec<u8> {
+    let mut subscribers = subscribers.lock().await;
+    let Some(subscriber) = subscribers.get_mut(&id) else {
+        return Vec::new();
+    };
+    if request.cmd == CommandType::KSubscribe {
+        return watch_keys(subscriber, request.namespace.clone(), &request.args)
+            .unwrap_or_else(|e| build_error_response(&e));
+    }
+    let (kind, current): (&str, Vec<String>) = match request.cmd {
+        CommandType::Subscribe => ("subscribe", Vec::new()),
+        CommandType::Unsubscribe => ("unsubscribe", subscriber.channels.iter().cloned().collect()),
+        CommandType::PSubscribe => ("psubscribe", Vec::new()),
+        CommandType::PUnsubscribe => (
+            "punsubscribe",
+            subscriber.patterns.iter().cloned().collect(),
+        ),
+        CommandType::KUnsubscribe => (
+            "kunsubscribe",
+            subscriber
+                .key_patterns
+                .keys()
+                .filter(|(namespace, _)| *namespace == request.namespace)
+                .map(|(_, pattern)| pattern.clone())
+                .collect(),
+        ),
+        _ => return build_error_response(&Error::from_code(ErrorCode::Subscribed)),
+    };
+    let names: Vec<String> = match request.cmd {
+        CommandType::Subscribe | CommandType::PSubscribe if request.args.is_empty() => {
+            return build_error_response(&Error::from_code(ErrorCode::NotEnoughArgs))
+        }
+        _ if request.args.is_empty() => current,
+        _ => request.args.clone(),
+    };
+
+    let mut response = Vec::new();
+    for name in names {
+        match request.cmd {
+            CommandType::Subscribe => {
+                subscriber.channels.insert(name.clone());
+            }
+            CommandType::Unsubscribe => {
+                subscriber.channels.remove(&name);
+            }
+            CommandType::PSubscribe => {
+                subscriber.patterns.insert(name.clone());
+            }
+            CommandType::PUnsubscribe => {
+                subscriber.patterns.remove(&name);
+            }
+            _ => {
+                subscriber
+                    .key_patterns
+                    .remove(&(request.namespace.clone(), name.clone()));
+            }
+        }
+        response.extend(build_subscription_response(
+            kind,
+            &name,
+            subscriber.subscription_count(),
+        ));
+    }
+    response
+}
+
+/// `KSUBSCRIBE pattern [event ...]`: watches keys of `namespace` matching `pattern` for the
+/// given event types, or for every type when none are listed. Watching a pattern again
+/// replaces its events.
+fn watch_keys(
+    subscriber: &mut Subscriber,
+    namespace: Option<String>,
+    args: &[String],
+) -> Result<Vec<u8>, Error> {
+    let (pattern, events) = args
+        .split_first()
+        .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))?;
+    let events: HashSet<KeyEvent> = if events.is_empty() {
+        KeyEvent::ALL.into_iter().collect()
+    } else {
+        events
+            .iter()
+            .map(|event| event.parse())
+            .collect::<Result<_, _>>()?
+    };
+    subscriber
+        .key_patterns
+        .insert((namespace, pattern.clone()), events);
+    Ok(build_subscription_response(
+        "ksubscribe",
+        pattern,
+        subscriber.subscription_count(),
+    ))
+}
+
+/// `PUBLISH channel message`: pushes the message to every subscriber of the channel or of a
+/// matching pattern and answers with the number of deliveries.
+async fn handle_publish(
+    socket: &mut TcpStream,
+    request: &ParsedRequest,
+    subscribers: &Subscribers,
+) {
+    let (Some(channel), Some(message)) = (request.args.first(), &request.value) else {
+        socket
+            .write_all(&build_error_response(&Error::from_code(
+                ErrorCode::NotEnoughArgs,
+            )))
+            .await
+            .unwrap();
+        return;
+    };
+
+    let mut deliveries = Vec::new();
+    for (id, subscriber) in subscribers.lock().await.iter() {
+        if subscriber.channels.contains(channel) {
+            deliveries.push((
+                *id,
+                subscriber.conn.clone(),
+                build_message_response(channel, message),
+            ));
+        }
+        for pattern in subscriber.patterns.iter() {
+            if glob_match(pattern, channel) {
+                deliveries.push((
+                    *id,
+                    subscriber.conn.clone(),
+                    build_pattern_message_response(pattern, channel, message),
+                ));
+            }
         }
     }
+
+    let delivered = deliver(deliveries, subscribers).await;
+    socket
+        .write_all(&build_value_response(&delivered.to_string()))
+        .await
+        .unwrap();
+}
+
+type Delivery = (Uuid, Arc<Mutex<OwnedWriteHalf>>, Vec<u8>);
+
+/// Writes each frame to its subscriber, dropping subscribers that can no longer be reached.
+/// Returns the number of frames delivered.
+async fn deliver(deliveries: Vec<Delivery>, subscribers: &Subscribers) -> usize {
+    let mut delivered = 0;
+    for (id, conn, frame) in deliveries {
+        match conn.lock().await.write_all(&frame).await {
+            Ok(_) => delivered += 1,
+            Err(e) => {
+                event!(Level::DEBUG, "Dropping subscriber {}: {:?}", id, e);
+                subscribers.lock().await.remove(&id);
+            }
+        }
+    }
+    delivered
+}
+
+/// Whether `args` are exactly a token handed out to a registering partition.
+fn is_event_token(args: &[String], event_tokens: &EventTokens) -> bool {
+    match args {
+        [token] => token
+            .parse::<Uuid>()
+            .is_ok_and(|token| event_tokens.lock().unwrap().contains(&token)),
+        _ => false,
+    }
 }
 
-async fn handle_list(mut socket: TcpStream, partition_set: PartitionSet) {
+/// Serves a partition's event stream, opened with a bare `EVT`, and forwards every
+/// `EVT <event> <key>` frame to the subscribers watching that key in the frame's namespace.
+async fn handle_event_stream(mut socket: TcpStream, subscribers: Subscribers) {
+    let partition_addr = socket.peer_addr().unwrap();
+    event!(
+        Level::DEBUG,
+        "Event stream from partition {:?}",
+        partition_addr
+    );
+    socket.write_all(&build_ack_response()).await.unwrap();
+
+    let mut pending = Vec::new();
+    let mut buf = vec![0; 4096];
+    loop {
+        let read_amount = match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(amount) => amount,
+        };
+        pending.extend_from_slice(&buf[..read_amount]);
+        // one read may carry several frames, and the last one may be incomplete
+        while let Some(end) = pending.iter().position(|&c| c == b'\0') {
+            let frame: Vec<u8> = pending.drain(..=end).collect();
+            let request = match parse_request(frame) {
+                Ok(request) => request,
+                Err(e) => {
+                    event!(Level::ERROR, "Invalid event frame: {:?}", e);
+                    continue;
+                }
+            };
+            let (Some(event), Some(key)) = (
+                request
+                    .args
+                    .first()
+                    .and_then(|e| e.parse::<KeyEvent>().ok()),
+                request.args.get(1),
+            ) else {
+                event!(Level::ERROR, "Invalid event: {}", request.original_rq);
+                continue;
+            };
+            let deliveries: Vec<Delivery> = subscribers
+                .lock()
+                .await
+                .iter()
+                .filter(|(_, subscriber)| {
+                    subscriber.watches(event, request.namespace.as_deref(), key)
+                })
+                .map(|(id, subscriber)| {
+                    (
+                        *id,
+                        subscriber.conn.clone(),
+                        build_event_response(event, key),
+                    )
+                })
+                .collect();
+            deliver(deliveries, &subscribers).await;
+        }
+    }
+    event!(
+        Level::DEBUG,
+        "Event stream from partition {:?} closed",
+        partition_addr
+    );
+}
+
+/// Serves a connection that starts a transaction. Before `MULTI`, keyed requests are served
+/// right away so the client can read the keys it watches; after it they are queued until
+/// `EXEC` hands them to their partition as one batch. The connection is served until the
+/// transaction ends.
+async fn handle_transaction(
+    socket: &mut TcpStream,
+    request: ParsedRequest,
+    selected: Option<&str>,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+    config: &Config,
+) {
+    let mut transaction = Transaction {
+        namespace: request.namespace.clone(),
+        ..Transaction::default()
+    };
+    let mut next = Ok(request);
+    loop {
+        let in_multi = transaction.queued.is_some();
+        let response = match next {
+            Ok(request) if request.namespace != transaction.namespace => {
+                build_error_response(&Error::from_code(ErrorCode::TransactionState))
+            }
+            Ok(request) => match request.cmd {
+                CommandType::Multi if !in_multi => {
+                    transaction.queued = Some(Vec::new());
+                    build_ok_response()
+                }
+                CommandType::Watch if !in_multi => {
+                    let namespace = transaction.namespace.as_deref();
+                    match watch_versions(&request.args, namespace, ring, partition_set).await {
+                        Ok(versions) => {
+                            transaction.watches.extend(versions);
+                            build_ok_response()
+                        }
+                        Err(e) => build_error_response(&e),
+                    }
+                }
+                CommandType::Unwatch if !in_multi => {
+                    transaction.watches.clear();
+                    build_ok_response()
+                }
+                CommandType::Discard if in_multi => {
+                    transaction = Transaction::default();
+                    build_ok_response()
+                }
+                CommandType::Exec if in_multi => {
+                    let transaction = std::mem::take(&mut transaction);
+                    exec_transaction(transaction, ring, partition_set).await
+                }
+                ref cmd if cmd.has_key() && in_multi => {
+                    transaction
+                        .queued
+                        .get_or_insert_with(Vec::new)
+                        .push(request);
+                    build_queued_response()
+                }
+                ref cmd if cmd.has_key() => {
+                    forward_to_partition(
+                        socket,
+                        &request,
+                        ring.clone(),
+                        partition_set.clone(),
+                        config,
+                    )
+                    .await;
+                    // forwarding has already answered the client
+                    Vec::new()
+                }
+                _ => build_error_response(&Error::from_code(ErrorCode::TransactionState)),
+            },
+            Err(e) => build_error_response(&e),
+        };
+        if socket.write_all(&response).await.is_err() || !transaction.is_open() {
+            return;
+        }
+
+        let mut buf = [0; 4096];
+        next = match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(amount) => parse_request(buf[..amount].to_vec()).map(|mut request| {
+                select_namespace(&mut request, selected);
+                request
+            }),
+        };
+    }
+}
+
+/// The partition that owns `key`, ignoring bounded loads. Transactions and locks use it so
+/// that every request for a key resolves the same way.
+async fn owner_partition(key: &str, ring: &Ring) -> Result<Partition, Error> {
+    ring.lock()
+        .await
+        .get_node(hash_tag(key))
+        .cloned()
+        .ok_or_else(|| Error::from_code(ErrorCode::NoPartitionsInRing))
+}
+
+/// Current versions of `keys` in `namespace`, asking each owning partition once.
+async fn watch_versions(
+    keys: &[String],
+    namespace: Option<&str>,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) -> Result<Vec<(String, u64)>, Error> {
+    if keys.is_empty() {
+        return Err(Error::from_code(ErrorCode::NotEnoughArgs));
+    }
+    let mut by_partition: Vec<(Partition, Vec<String>)> = Vec::new();
+    for key in keys {
+        let partition = owner_partition(key, ring).await?;
+        match by_partition.iter_mut().find(|(p, _)| *p == partition) {
+            Some((_, keys)) => keys.push(key.clone()),
+            None => by_partition.push((partition, vec![key.clone()])),
+        }
+    }
+
+    let mut versions = Vec::with_capacity(keys.len());
+    for (partition, keys) in by_partition {
+        let _in_flight = partition.track_request();
+        let request = build_namespaced_request(namespace, &build_watch_request(&keys));
+        let response = match exchange(&partition, &request).await {
+            Ok(response) => response,
+            Err(_) => {
+                unregister_partition(&partition, ring.clone(), partition_set.clone()).await;
+                return Err(Error::from_code(ErrorCode::PartitionUnavailable));
+            }
+        };
+        let watched = parse_array_response(&response)
+            .filter(|items| items.len() == keys.len())
+            .ok_or_else(|| Error::from_code(ErrorCode::Unknown))?;
+        for (key, version) in keys.into_iter().zip(watched) {
+            let version = version
+                .parse()
+                .map_err(|_| Error::from_code(ErrorCode::Unknown))?;
+            versions.push((key, version));
+        }
+    }
+    Ok(versions)
+}
+
+/// Sends a transaction to the single partition owning all of its keys, answering with the
+/// partition's `EXC` response, or `NST` when a watched key changed.
+async fn exec_transaction(
+    transaction: Transaction,
+    ring: &Ring,
+    partition_set: &PartitionSet,
+) -> Vec<u8> {
+    let requests = transaction.queued.unwrap_or_default();
+    let keys = transaction
+        .watches
+        .iter()
+        .map(|(key, _)| key.as_str())
+        .chain(requests.iter().flat_map(ParsedRequest::keys));
+    let mut owner: Option<Partition> = None;
+    for key in keys {
+        let partition = match owner_partition(key, ring).await {
+            Ok(partition) => partition,
+            Err(e) => return build_error_response(&e),
+        };
+        match &owner {
+            Some(owner) if *owner != partition => {
+                return build_error_response(&Error::from_code(ErrorCode::CrossPartition))
+            }
+            _ => owner = Some(partition),
+        }
+    }
+    let Some(partition) = owner else {
+        return build_exec_response(&[]);
+    };
+
+    let batch = ExecBatch {
+        watches: transaction.watches,
+        requests: requests
+            .into_iter()
+            .map(|request| request.original_rq)
+            .collect(),
+    };
+    let _in_flight = partition.track_request();
+    let request = build_namespaced_request(
+        transaction.namespace.as_deref(),
+        &build_exec_request(&batch),
+    );
+    match exchange(&partition, &request).await {
+        Ok(response) => response,
+        Err(_) => {
+            unregister_partition(&partition, ring.clone(), partition_set.clone()).await;
+            build_error_response(&Error::from_code(ErrorCode::PartitionUnavailable))
+        }
+    }
+}
+
+async fn handle_list(socket: &mut TcpStream, partition_set: &PartitionSet) {
     let partitions = partition_set
         .lock()
         .await
@@ -315,15 +1463,142 @@ async fn handle_list(mut socket: TcpStream, partition_set: PartitionSet) {
         .unwrap();
 }
 
-async fn handle_notify(mut socket: TcpStream, ring: Ring, partition_set: PartitionSet) {
+/// Weight advertised by a registering partition, defaulting to 1 for partitions that send none.
+/// Weights above `MAX_WEIGHT` are rejected.
+fn parse_weight(request: &ParsedRequest) -> Result<u32, Error> {
+    match request.args.first() {
+        None => Ok(1),
+        Some(weight) => weight
+            .parse::<u32>()
+            .ok()
+            .filter(|weight| (1..=MAX_WEIGHT).contains(weight))
+            .ok_or_else(|| Error::from_code(ErrorCode::InvalidRequestArg)),
+    }
+}
+
+async fn handle_notify(
+    mut socket: TcpStream,
+    ring: Ring,
+    partition_set: PartitionSet,
+    event_tokens: &EventTokens,
+    weight: u32,
+) {
     let partition_addr = socket.peer_addr().unwrap();
-    event!(Level::DEBUG, "NTF from partition: {:?}", partition_addr,);
-    socket.write_all(&build_ack_response()).await.unwrap();
+    event!(
+        Level::DEBUG,
+        "NTF from partition: {:?} with weight {}",
+        partition_addr,
+        weight
+    );
+    let token = Uuid::new_v4();
+    event_tokens.lock().unwrap().insert(token);
+    socket
+        .write_all(&build_notify_ack_response(&token.to_string()))
+        .await
+        .unwrap();
 
-    let partition = Partition::new(partition_addr.to_string(), Arc::new(Mutex::new(socket)));
+    let partition = Partition::new(
+        partition_addr.to_string(),
+        Arc::new(PartitionLink::open(socket, partition_addr.to_string())),
+        weight,
+    );
 
-    ring.lock().await.add_node(&partition);
+    ring.lock().await.add_node(&partition, weight);
     partition_set.lock().await.insert(partition.clone());
 
     event!(Level::DEBUG, "{:?} successfully added to ring", partition,);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A partition that answers every request with `answer`, counting the requests in `asked`.
+    async fn fake_partition(answer: &'static str, asked: Arc<AtomicUsize>) -> Partition {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut pending = Vec::new();
+            let mut buf = vec![0; 4096];
+            while let Ok(amount @ 1..) = socket.read(&mut buf).await {
+                pending.extend_from_slice(&buf[..amount]);
+                while let Some(end) = pending.iter().position(|&c| c == b'\0') {
+                    let frame: Vec<u8> = pending.drain(..=end).collect();
+                    let (id, _) = split_tag(&frame).unwrap();
+                    asked.fetch_add(1, Ordering::SeqCst);
+                    let response = build_tagged_frame(id, answer.as_bytes());
+                    socket.write_all(&response).await.unwrap();
+                }
+            }
+        });
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let link = Arc::new(PartitionLink::open(socket, addr.clone()));
+        Partition::new(addr, link, 1)
+    }
+
+    #[tokio::test]
+    async fn spilled_reads_still_find_the_owners_value() {
+        let (owner_asked, fallback_asked) = (Arc::new(AtomicUsize::new(0)), Arc::default());
+        let owner = fake_partition("HIT k 1 v", owner_asked.clone()).await;
+        let fallback = fake_partition("MSS k", Arc::clone(&fallback_asked)).await;
+        let mut placement = PlacementKind::Ring.build::<Partition>(1);
+        placement.add_node(&owner, 1);
+        placement.add_node(&fallback, 1);
+        let key = (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| placement.get_node(key) == Some(&owner))
+            .unwrap();
+        let ring: Ring = Arc::new(Mutex::new(placement));
+        let partition_set: PartitionSet =
+            Arc::new(Mutex::new([owner.clone(), fallback.clone()].into()));
+        let config = Config {
+            load_factor: Some(1.25),
+            ..Config::default()
+        };
+        // keep the owner busy, so that bounded loads pass its keys on
+        let _busy: Vec<InFlightGuard> = (0..10).map(|_| owner.track_request()).collect();
+
+        let get = parse_request(format!("GET {}", key).into_bytes()).unwrap();
+        let response = forward_request(&get, &ring, &partition_set, &config).await;
+        assert_eq!(response, b"HIT k 1 v\0");
+        assert_eq!(fallback_asked.load(Ordering::SeqCst), 1);
+        assert_eq!(owner_asked.load(Ordering::SeqCst), 1);
+
+        // filter reads can't tell a miss from an absent item, so they never spill
+        let exists = parse_request(format!("BF.EXISTS {} item", key).into_bytes()).unwrap();
+        forward_request(&exists, &ring, &partition_set, &config).await;
+        assert_eq!(fallback_asked.load(Ordering::SeqCst), 1);
+        assert_eq!(owner_asked.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn failed_forwards_are_reported_by_failure_mode() {
+        let get = parse_request(b"GET a BEST_EFFORT".to_vec()).unwrap();
+        let set = parse_request(b"SET a 1".to_vec()).unwrap();
+        let incr = parse_request(b"INCR a".to_vec()).unwrap();
+        let unavailable = build_error_response(&Error::from_code(ErrorCode::PartitionUnavailable));
+
+        assert_eq!(
+            failed_forward_response(&get, FailureMode::Strict),
+            unavailable
+        );
+        assert_eq!(
+            failed_forward_response(&set, FailureMode::Strict),
+            unavailable
+        );
+        // the key has a space in it, the status word still tells a best-effort miss apart
+        assert_eq!(
+            failed_forward_response(&get, FailureMode::BestEffort),
+            b"BMS a BEST_EFFORT\0"
+        );
+        assert_eq!(
+            failed_forward_response(&set, FailureMode::BestEffort),
+            b"OK BEST_EFFORT\0"
+        );
+        assert_eq!(
+            failed_forward_response(&incr, FailureMode::BestEffort),
+            unavailable
+        );
+    }
+}
@@ -1,121 +1,278 @@
 use core::panic;
+use hitormiss::backend::{Backend, BackendHandle, FileBackend, SqliteBackend, WritePolicy};
+use hitormiss::disk::DiskTier;
 use hitormiss::error::{Error, ErrorCode};
 use hitormiss::parser::{
-    build_error_response, build_hit_response, build_lsd_response, build_miss_response,
-    build_notify_request, build_ok_response, parse_request, CommandType,
+    build_array_response, build_error_response, build_event_request, build_event_stream_request,
+    build_exec_response, build_hit_response, build_lease_response, build_lsd_response,
+    build_meta_response, build_miss_response, build_namespaced_request, build_not_stored_response,
+    build_notify_request, build_ok_response, build_scan_response, build_stale_response,
+    build_stats_response, build_tagged_frame, build_value_response, build_wait_response,
+    decode_cursor_key, encode_cursor_key, parse_exec_request, parse_request, parse_scan_args,
+    parse_score_bound, parse_value_response, split_set_options, split_tag, CommandType, ExecBatch,
+    ParsedRequest,
 };
-use lru::LruCache;
+use hitormiss::placement::MAX_WEIGHT;
+use hitormiss::sketch::HyperLogLog;
+use hitormiss::store::{Namespaces, SetCondition, Store, DEFAULT_NAMESPACE};
+use hitormiss::throttle::RateLimit;
+use std::collections::HashMap;
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tracing::{event, Level};
 
+/// How long a client handed a lease by `LGET` has to fill the key, unless it asks otherwise.
+const DEFAULT_LEASE: Duration = Duration::from_secs(10);
+/// How often an idle partition looks for expired keys.
+const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+/// Size cap of the disk tier's log, unless given another.
+const DEFAULT_DISK_TIER_BYTES: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+struct Config {
+    /// Share of the ring this partition asks the master for, relative to other partitions.
+    weight: u32,
+    /// Most keys the partition holds across all namespaces.
+    capacity: NonZeroUsize,
+    /// Namespaces besides the default one, each with its weight in the capacity split.
+    namespaces: Vec<(String, u32)>,
+    /// Storage keys are loaded from on a miss and written to, if any.
+    backend: Option<BackendConfig>,
+    /// Writes reach the backend this often instead of before each answer, when set.
+    write_behind: Option<Duration>,
+    /// Log file evicted entries are spilled to, if any.
+    disk_tier: Option<PathBuf>,
+    /// Size cap of the disk tier's log in bytes.
+    disk_tier_bytes: u64,
+}
+
+#[derive(Debug, Clone)]
+enum BackendConfig {
+    File(PathBuf),
+    Sqlite(PathBuf),
+}
+
+impl BackendConfig {
+    /// `file:<dir>` or `sqlite:<path>`.
+    fn parse(arg: &str) -> Option<Self> {
+        match arg.split_once(':')? {
+            ("file", dir) if !dir.is_empty() => Some(Self::File(dir.into())),
+            ("sqlite", path) if !path.is_empty() => Some(Self::Sqlite(path.into())),
+            _ => None,
+        }
+    }
+
+    fn open(&self) -> Result<Box<dyn Backend>, Error> {
+        Ok(match self {
+            Self::File(dir) => Box::new(FileBackend::new(dir)?),
+            Self::Sqlite(path) => Box::new(SqliteBackend::open(path)?),
+        })
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            weight: 1,
+            capacity: NonZeroUsize::new(2).unwrap(),
+            namespaces: Vec::new(),
+            backend: None,
+            write_behind: None,
+            disk_tier: None,
+            disk_tier_bytes: DEFAULT_DISK_TIER_BYTES,
+        }
+    }
+}
+
+impl Config {
+    fn from_args() -> Self {
+        let mut config = Config::default();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--weight" => match args.next().and_then(|w| w.parse::<u32>().ok()) {
+                    Some(weight) if (1..=MAX_WEIGHT).contains(&weight) => config.weight = weight,
+                    _ => panic!("--weight expects an integer from 1 to {}", MAX_WEIGHT),
+                },
+                "--capacity" => match args.next().and_then(|c| c.parse::<NonZeroUsize>().ok()) {
+                    Some(capacity) => config.capacity = capacity,
+                    None => panic!("--capacity expects a positive integer"),
+                },
+                "--namespace" => match args.next().as_deref().and_then(parse_namespace) {
+                    Some(namespace) => config.namespaces.push(namespace),
+                    None => panic!("--namespace expects <name> or <name>:<weight>"),
+                },
+                "--backend" => match args.next().as_deref().and_then(BackendConfig::parse) {
+                    Some(backend) => config.backend = Some(backend),
+                    None => panic!("--backend expects file:<dir> or sqlite:<path>"),
+                },
+                "--write-behind" => match args.next().and_then(|ms| ms.parse::<u64>().ok()) {
+                    Some(ms) if ms > 0 => config.write_behind = Some(Duration::from_millis(ms)),
+                    _ => panic!("--write-behind expects a positive number of milliseconds"),
+                },
+                "--disk-tier" => match args.next() {
+                    Some(path) => config.disk_tier = Some(path.into()),
+                    None => panic!("--disk-tier expects a file path"),
+                },
+                "--disk-tier-bytes" => match args.next().and_then(|b| b.parse::<u64>().ok()) {
+                    Some(bytes) if bytes > 0 => config.disk_tier_bytes = bytes,
+                    _ => panic!("--disk-tier-bytes expects a positive integer"),
+                },
+                _ => event!(Level::WARN, "Ignoring unknown argument: {}", arg),
+            }
+        }
+        config
+    }
+}
+
+/// `<name>:<weight>`, or just `<name>` for a weight of 1.
+fn parse_namespace(arg: &str) -> Option<(String, u32)> {
+    let (name, weight) = match arg.split_once(':') {
+        Some((name, weight)) => (name, weight.parse::<u32>().ok().filter(|w| *w > 0)?),
+        None => (arg, 1),
+    };
+    let valid = !name.is_empty() && !name.contains(char::is_whitespace);
+    valid.then(|| (name.to_string(), weight))
+}
+
 #[tokio::main]
 pub async fn main() {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    let config = Config::from_args();
+    event!(Level::INFO, "Partition configuration: {:?}", config);
+
     let master_addr = String::from("127.0.0.1:6969");
     let mut stream = TcpStream::connect(&master_addr).await.unwrap();
 
-    notify_master(&mut stream).await;
+    let token = notify_master(&mut stream, config.weight).await;
+    let mut events = open_event_stream(&master_addr, &token).await;
 
-    let mut cache = LruCache::<String, String>::new(NonZeroUsize::new(2).unwrap());
+    let mut namespaces = Namespaces::new(config.capacity, &config.namespaces);
+    let backend = config.backend.as_ref().map(|backend| {
+        let policy = config
+            .write_behind
+            .map_or(WritePolicy::Through, WritePolicy::Behind);
+        match backend.open() {
+            Ok(backend) => BackendHandle::new(backend, policy),
+            Err(e) => panic!("failed to open backend: {}", e.msg),
+        }
+    });
 
-    loop {
-        let mut buf = [0; 4096];
-        match stream.read(&mut buf).await {
-            Ok(_) => {
-                let v = buf.to_vec();
-                let parsed_request = match parse_request(v) {
-                    Ok(parsed_request) => {
-                        event!(
-                            Level::INFO,
-                            "Successfully parsed message {}",
-                            parsed_request.original_rq
-                        );
-                        parsed_request
-                    }
-                    Err(e) => {
-                        stream.write_all(&build_error_response(&e)).await.unwrap();
-                        continue;
-                    }
-                };
+    let disk = config.disk_tier.as_ref().map(|path| {
+        for (_, store) in namespaces.iter_mut() {
+            store.keep_evicted();
+        }
+        match DiskTier::open(path, config.disk_tier_bytes) {
+            Ok(disk) => disk,
+            Err(e) => panic!("failed to open disk tier: {}", e),
+        }
+    });
+    let mut tiers = Tiers::new(disk);
 
-                match parsed_request.cmd {
-                    CommandType::Get => {
-                        if let Some(key) = parsed_request.key {
-                            match cache.get(&key) {
-                                Some(value) => {
-                                    stream
-                                        .write_all(&build_hit_response(&key, value))
-                                        .await
-                                        .unwrap();
-                                }
-                                None => {
-                                    stream.write_all(&build_miss_response(&key)).await.unwrap();
-                                }
-                            }
-                        } else {
-                            stream
-                                .write_all(&build_error_response(&Error::from_code(
-                                    ErrorCode::NotEnoughArgs,
-                                )))
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    CommandType::Lsd => {
-                        stream.write_all(&build_lsd_response(&cache)).await.unwrap();
-                    }
-                    CommandType::Set => {
-                        if let (Some(key), Some(value)) = (parsed_request.key, parsed_request.value)
-                        {
-                            cache.put(key, value);
-                            stream.write_all(&build_ok_response()).await.unwrap();
-                        } else {
-                            stream
-                                .write_all(&build_error_response(&Error::from_code(
-                                    ErrorCode::NotEnoughArgs,
-                                )))
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    CommandType::Delete => {
-                        if let Some(key) = parsed_request.key {
-                            cache.pop(&key);
-                            stream.write_all(&build_ok_response()).await.unwrap();
-                        } else {
-                            stream
-                                .write_all(&build_error_response(&Error::from_code(
-                                    ErrorCode::NotEnoughArgs,
-                                )))
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    _ => {
-                        stream
-                            .write_all(&build_error_response(&Error::from_code(
-                                ErrorCode::InvalidRequestCmd,
-                            )))
-                            .await
-                            .unwrap();
+    // expired keys are purged before each request, and on this timer while idle so that
+    // their events still go out
+    let mut sweep = tokio::time::interval(EXPIRY_SWEEP_INTERVAL);
+
+    let mut loads = Loads::default();
+    let (loaded_sender, mut loaded) = mpsc::unbounded_channel();
+
+    // requests are `\0`-terminated and a large one may take several reads
+    let mut pending = Vec::new();
+    let mut buf = vec![0; 4096];
+    loop {
+        let Some(end) = pending.iter().position(|&c| c == b'\0') else {
+            tokio::select! {
+                read = stream.read(&mut buf) => match read {
+                    Ok(0) => panic!("master closed the connection"),
+                    Ok(amount) => pending.extend_from_slice(&buf[..amount]),
+                    Err(e) => panic!("error: {e}"),
+                },
+                Some((id, results)) = loaded.recv() => {
+                    purge_expired(&mut namespaces);
+                    let responses =
+                        loads.finish(&mut namespaces, backend.as_ref(), id, results, &loaded_sender);
+                    for (tag, response) in responses {
+                        stream.write_all(&build_tagged_frame(tag, &response)).await.unwrap();
                     }
+                    tiers.demote(&mut namespaces);
+                    publish_events(&mut events, &mut namespaces).await;
+                }
+                _ = sweep.tick() => {
+                    purge_expired(&mut namespaces);
+                    publish_events(&mut events, &mut namespaces).await;
                 }
             }
+            continue;
+        };
+        let frame: Vec<u8> = pending.drain(..=end).collect();
+        let Some((tag, frame)) = split_tag(&frame) else {
+            event!(Level::ERROR, "Dropping untagged request from master");
+            continue;
+        };
+        let parsed_request = match parse_request(frame.to_vec()) {
+            Ok(parsed_request) => {
+                event!(
+                    Level::INFO,
+                    "Successfully parsed message {}",
+                    parsed_request.original_rq
+                );
+                parsed_request
+            }
             Err(e) => {
-                panic!("error: {e}");
+                let response = build_error_response(&e);
+                stream
+                    .write_all(&build_tagged_frame(tag, &response))
+                    .await
+                    .unwrap();
+                continue;
             }
+        };
+
+        purge_expired(&mut namespaces);
+        let response = match parsed_request.cmd {
+            CommandType::FlushAll => {
+                let dropped = tiers.clear();
+                loads.dirty_all();
+                Some(add_to_count(flush_all(&mut namespaces), dropped))
+            }
+            CommandType::Stats => Some(tiers.stats()),
+            _ => match namespaces.get_mut(parsed_request.namespace.as_deref()) {
+                Ok(store) => {
+                    tiers.promote(store, &parsed_request);
+                    let dropped = tiers.drop_removed(&parsed_request);
+                    loads
+                        .serve(store, backend.as_ref(), tag, parsed_request, &loaded_sender)
+                        .map(|response| add_to_count(response, dropped))
+                }
+                Err(e) => Some(build_error_response(&e)),
+            },
+        };
+        // requests waiting on a backend load are answered once it is done
+        if let Some(response) = response {
+            stream
+                .write_all(&build_tagged_frame(tag, &response))
+                .await
+                .unwrap();
         }
+        tiers.demote(&mut namespaces);
+        publish_events(&mut events, &mut namespaces).await;
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hitormiss::parser::build_exec_request;
+    use lru::LruCache;
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn check_if_value_was_added_to_cache() {
@@ -123,22 +280,1314 @@ mod tests {
         cache.put(String::from("Name"), String::from("Fjoni"));
         assert_eq!(*cache.get("Name").unwrap(), "Fjoni");
     }
+
+    /// A backend whose saves wait for the test to let each one through.
+    struct GatedBackend {
+        gate: std::sync::mpsc::Receiver<()>,
+        saved: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Backend for GatedBackend {
+        fn load(&mut self, _: &str, _: &str) -> Result<Option<String>, Error> {
+            Ok(self.saved.lock().unwrap().last().cloned())
+        }
+
+        fn save(&mut self, _: &str, _: &str, value: &str) -> Result<(), Error> {
+            self.gate.recv().unwrap();
+            self.saved.lock().unwrap().push(value.to_string());
+            Ok(())
+        }
+
+        fn remove(&mut self, _: &str, _: &str) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn transactions_wait_for_writes_in_flight_on_their_keys() {
+        let (open, gate) = std::sync::mpsc::channel();
+        let saved = Arc::new(Mutex::new(Vec::new()));
+        let backend = GatedBackend {
+            gate,
+            saved: saved.clone(),
+        };
+        let backend = BackendHandle::new(Box::new(backend), WritePolicy::Through);
+        let mut namespaces = Namespaces::new(NonZeroUsize::new(8).unwrap(), &[]);
+        let mut loads = Loads::default();
+        let (sender, mut landed) = mpsc::unbounded_channel();
+
+        let store = namespaces.get_mut(None).unwrap();
+        store.set("k".to_string(), "zero".to_string());
+        let set = parse_request(b"SET k first\0".to_vec()).unwrap();
+        assert_eq!(loads.serve(store, Some(&backend), 1, set, &sender), None);
+        let batch = ExecBatch {
+            watches: Vec::new(),
+            requests: vec!["SET k second".to_string()],
+        };
+        let exec = parse_request(build_exec_request(&batch)).unwrap();
+        assert_eq!(loads.serve(store, Some(&backend), 2, exec, &sender), None);
+
+        // the transaction only runs once the pending write landed, and answers once its own did
+        let mut answered = Vec::new();
+        for expected in [vec![1], vec![1, 2]] {
+            open.send(()).unwrap();
+            let (id, results) = landed.recv().await.unwrap();
+            answered.extend(loads.finish(&mut namespaces, Some(&backend), id, results, &sender));
+            let tags: Vec<u64> = answered.iter().map(|(tag, _)| *tag).collect();
+            assert_eq!(tags, expected);
+        }
+        assert_eq!(*saved.lock().unwrap(), ["first", "second"]);
+        let store = namespaces.get_mut(None).unwrap();
+        assert_eq!(store.peek("k").unwrap().value.as_str().unwrap(), "second");
+    }
+}
+
+/// Where the keys requests ask for are found, and the disk tier entries evicted from memory
+/// are spilled to, if there is one. The disk tier is shared by all namespaces. Keys on it are
+/// left out of `DBSIZE` and `SCAN`, which only see memory.
+struct Tiers {
+    disk: Option<DiskTier>,
+    /// Keys read by `GET`, `LGET` and `GETMETA` since the partition started, by where they
+    /// were found: in memory, on disk, or nowhere. Keys a backend then loads count as misses.
+    /// Other commands promote keys from disk without being counted.
+    memory_hits: u64,
+    disk_hits: u64,
+    misses: u64,
+}
+
+impl Tiers {
+    fn new(disk: Option<DiskTier>) -> Self {
+        Self {
+            disk,
+            memory_hits: 0,
+            disk_hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Looks up the keys `request` touches, bringing those on disk back into memory.
+    fn promote(&mut self, store: &mut Store, request: &ParsedRequest) {
+        let namespace = request_namespace(request);
+        let now = Instant::now();
+        let counted = matches!(
+            request.cmd,
+            CommandType::Get | CommandType::LGet | CommandType::GetMeta
+        );
+        for key in touched_keys(request) {
+            if store.peek(&key).is_some() {
+                self.memory_hits += counted as u64;
+                continue;
+            }
+            let spilled = match self.disk.as_mut().map(|disk| disk.take(&namespace, &key)) {
+                Some(Ok(spilled)) => spilled,
+                Some(Err(e)) => {
+                    event!(Level::ERROR, "Failed to read {} from disk: {}", key, e);
+                    None
+                }
+                None => None,
+            };
+            match spilled.filter(|entry| !entry.is_expired(now)) {
+                Some(entry) => {
+                    self.disk_hits += counted as u64;
+                    store.restore(key, entry);
+                }
+                None => self.misses += counted as u64,
+            }
+        }
+    }
+
+    /// Spills the entries evicted from memory to disk. Only strings are spilled, other values
+    /// are dropped as they would be without a disk tier.
+    fn demote(&mut self, namespaces: &mut Namespaces) {
+        let Some(disk) = self.disk.as_mut() else {
+            return;
+        };
+        for (namespace, store) in namespaces.iter_mut() {
+            for (key, entry) in store.take_evicted() {
+                if let Err(e) = disk.spill(namespace, &key, entry) {
+                    event!(Level::ERROR, "Failed to spill {} to disk: {}", key, e);
+                }
+            }
+        }
+    }
+
+    /// Drops the keys on disk that `FLUSH` or `INVALIDATE` remove, returning how many.
+    fn drop_removed(&mut self, request: &ParsedRequest) -> usize {
+        let Some(disk) = self.disk.as_mut() else {
+            return 0;
+        };
+        let namespace = request_namespace(request);
+        match request.cmd {
+            CommandType::Flush => disk.flush(&namespace, request.args.first().map(String::as_str)),
+            CommandType::Invalidate => request
+                .args
+                .iter()
+                .map(|tag| disk.invalidate(&namespace, tag))
+                .sum(),
+            _ => 0,
+        }
+    }
+
+    fn clear(&mut self) -> usize {
+        self.disk.as_mut().map_or(0, DiskTier::clear)
+    }
+
+    /// `STS` with the lookup counters and the size of the disk tier.
+    fn stats(&self) -> Vec<u8> {
+        let (disk_keys, disk_bytes) = self
+            .disk
+            .as_ref()
+            .map_or((0, 0), |disk| (disk.len() as u64, disk.bytes()));
+        let stats = [
+            ("memory_hits", self.memory_hits),
+            ("disk_hits", self.disk_hits),
+            ("misses", self.misses),
+            ("disk_keys", disk_keys),
+            ("disk_bytes", disk_bytes),
+        ];
+        let stats: Vec<(String, u64)> = stats
+            .into_iter()
+            .map(|(name, count)| (name.to_string(), count))
+            .collect();
+        build_stats_response(&stats, &[])
+    }
+}
+
+/// Every key a request reads or writes, including those of a transaction's requests and
+/// the keys it watches.
+fn touched_keys(request: &ParsedRequest) -> Vec<String> {
+    match request.cmd {
+        CommandType::Watch => request.args.clone(),
+        CommandType::Exec => {
+            let Ok(batch) = parse_exec_request(&request.original_rq) else {
+                return Vec::new();
+            };
+            let mut keys: Vec<String> = batch.watches.into_iter().map(|(key, _)| key).collect();
+            for request in batch.requests {
+                if let Ok(request) = parse_request(request.into_bytes()) {
+                    keys.extend(touched_keys(&request));
+                }
+            }
+            keys
+        }
+        _ => request.keys().into_iter().map(String::from).collect(),
+    }
+}
+
+/// Adds `dropped` to the count in a `VAL <n>` answer.
+fn add_to_count(response: Vec<u8>, dropped: usize) -> Vec<u8> {
+    let count = parse_value_response(&response).and_then(|count| count.parse::<usize>().ok());
+    match count {
+        Some(count) if dropped > 0 => build_value_response(&(count + dropped).to_string()),
+        _ => response,
+    }
+}
+
+/// What the backend I/O of the flight with the given id came to: a key's value loaded from
+/// the backend, or `None` for a write that reached it. A transaction gets one for each key it
+/// loaded or wrote, in order.
+type Loaded = (u64, Vec<Result<Option<String>, Error>>);
+
+/// Backend loads and write-throughs in flight. Both run off the request loop, which keeps
+/// answering other keys meanwhile. Requests for a key with a load or write in flight wait
+/// for it, in order, so concurrent misses on a key make a single load and share its result.
+/// A transaction holds a flight on every key it touches while its loads and writes run.
+#[derive(Default)]
+struct Loads {
+    next_id: u64,
+    /// The load or write in flight for each key, by namespace and key.
+    flights: HashMap<(String, String), Flight>,
+    /// Transactions waiting for the backend, by the id of the flights they hold.
+    transactions: HashMap<u64, Transaction>,
+    /// Requests that were answered from a load started for an earlier request, by
+    /// namespace, since the partition started.
+    coalesced: HashMap<String, u64>,
+}
+
+struct Flight {
+    id: u64,
+    /// For a write, the tag of the request that made it and its response, sent once the
+    /// write has reached the backend.
+    writer: Option<(u64, Vec<u8>)>,
+    /// Requests for the key, tagged as they came from the master, in arrival order.
+    waiting: Vec<(u64, ParsedRequest)>,
+    /// Set when the key was written or deleted, or a transaction, flush or invalidation ran,
+    /// while the load or write was in flight. A loaded value may then be out of date, so it
+    /// is not cached, and the waiting requests go through the backend again.
+    dirty: bool,
+}
+
+struct Transaction {
+    /// The tag of the `EXEC` request, as it came from the master.
+    tag: u64,
+    namespace: Option<String>,
+    /// Every key it touches, each holding a flight until it is done.
+    keys: Vec<String>,
+    stage: Stage,
+}
+
+enum Stage {
+    /// Loading the keys its requests read through that were not cached, in order.
+    Loading(ExecBatch, Vec<String>),
+    /// Passing on its writes, once it ran and got `responses`.
+    Writing {
+        responses: Vec<Vec<u8>>,
+        written: Written,
+    },
+}
+
+/// Each string key a transaction wrote, with the indexes of the requests that wrote it, in
+/// the order the writes were made.
+type Written = Vec<(String, Vec<usize>)>;
+
+impl Loads {
+    /// Answers `request` right away, or returns `None` if it has to wait for a load or for
+    /// its write to reach the backend.
+    fn serve(
+        &mut self,
+        store: &mut Store,
+        backend: Option<&BackendHandle>,
+        tag: u64,
+        request: ParsedRequest,
+        loaded: &mpsc::UnboundedSender<Loaded>,
+    ) -> Option<Vec<u8>> {
+        let namespace = request_namespace(&request);
+        if request.cmd == CommandType::Coalesced {
+            let coalesced = self.coalesced.get(&namespace).copied().unwrap_or(0);
+            return Some(build_value_response(&coalesced.to_string()));
+        }
+        if matches!(request.cmd, CommandType::Flush | CommandType::Invalidate) {
+            self.dirty_all();
+        }
+        if request.cmd == CommandType::Exec {
+            return self.exec(store, backend, tag, request, loaded);
+        }
+        let (Some(backend), Some(key)) = (backend, request.key.clone()) else {
+            return Some(answer_request(store, backend, request));
+        };
+        let flight_key = (namespace, key);
+        if let Some(flight) = self.flights.get_mut(&flight_key) {
+            if reads_through(&request.cmd) {
+                *self.coalesced.entry(flight_key.0).or_default() += 1;
+            }
+            if writes_through(&request.cmd) {
+                flight.dirty = true;
+            }
+            flight.waiting.push((tag, request));
+            return None;
+        }
+        if !reads_through(&request.cmd) || store.peek(&flight_key.1).is_some() {
+            return self.answer(store, backend, tag, request, loaded);
+        }
+
+        let (backend, loaded) = (backend.clone(), loaded.clone());
+        let (namespace, key) = flight_key.clone();
+        let id = self.start(flight_key, None, vec![(tag, request)]);
+        tokio::task::spawn_blocking(move || {
+            let _ = loaded.send((id, vec![backend.load(&namespace, &key)]));
+        });
+        None
+    }
+
+    /// Runs a transaction once no load or write is in flight on any of its keys. With a
+    /// backend, it holds flights on all of them while the keys it reads through are loaded
+    /// and until its writes reached the backend, both off the request loop. Its requests
+    /// themselves run back to back on the loop, so no other request interleaves with them.
+    fn exec(
+        &mut self,
+        store: &mut Store,
+        backend: Option<&BackendHandle>,
+        tag: u64,
+        request: ParsedRequest,
+        loaded: &mpsc::UnboundedSender<Loaded>,
+    ) -> Option<Vec<u8>> {
+        let namespace = request_namespace(&request);
+        let mut keys = touched_keys(&request);
+        keys.sort();
+        keys.dedup();
+        let busy = keys.iter().find(|key| {
+            self.flights
+                .contains_key(&(namespace.clone(), key.to_string()))
+        });
+        if let Some(key) = busy {
+            let flight_key = (namespace, key.clone());
+            let flight = self.flights.get_mut(&flight_key).unwrap();
+            flight.waiting.push((tag, request));
+            return None;
+        }
+        let batch = match parse_exec_request(&request.original_rq) {
+            Ok(batch) => batch,
+            Err(e) => return Some(build_error_response(&e)),
+        };
+        let Some(backend) = backend else {
+            return Some(
+                match exec_batch(store, request.namespace, batch, Vec::new()) {
+                    Some((responses, _)) => build_exec_response(&responses),
+                    None => build_not_stored_response(),
+                },
+            );
+        };
+
+        let mut missing = read_through_keys(&batch);
+        missing.retain(|key| store.peek(key).is_none());
+        let transaction = Transaction {
+            tag,
+            namespace: request.namespace,
+            keys,
+            stage: Stage::Loading(batch, missing.clone()),
+        };
+        let id = self.hold(transaction);
+        if missing.is_empty() {
+            let responses = self.land(store, backend, id, Vec::new(), loaded);
+            return responses.into_iter().next().map(|(_, response)| response);
+        }
+        let (backend, loaded) = (backend.clone(), loaded.clone());
+        tokio::task::spawn_blocking(move || {
+            let values = missing
+                .iter()
+                .map(|key| backend.load(&namespace, key))
+                .collect();
+            let _ = loaded.send((id, values));
+        });
+        None
+    }
+
+    /// Starts flights on all of a transaction's keys, under one id.
+    fn hold(&mut self, transaction: Transaction) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let namespace = transaction
+            .namespace
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+        for key in &transaction.keys {
+            let flight = Flight {
+                id,
+                writer: None,
+                waiting: Vec::new(),
+                dirty: false,
+            };
+            self.flights
+                .insert((namespace.clone(), key.clone()), flight);
+        }
+        self.transactions.insert(id, transaction);
+        id
+    }
+
+    /// Moves a transaction on once its loads or writes are done: runs it and passes its
+    /// writes on, or answers it. Once answered, its flights end and the requests that waited
+    /// on them are served, and their responses are returned along with its own.
+    fn land(
+        &mut self,
+        store: &mut Store,
+        backend: &BackendHandle,
+        id: u64,
+        results: Vec<Result<Option<String>, Error>>,
+        loaded: &mpsc::UnboundedSender<Loaded>,
+    ) -> Vec<(u64, Vec<u8>)> {
+        let mut transaction = self.transactions.remove(&id).unwrap();
+        let namespace = transaction
+            .namespace
+            .clone()
+            .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string());
+        let response = match transaction.stage {
+            Stage::Loading(batch, missing) => {
+                match results.into_iter().collect::<Result<Vec<_>, _>>() {
+                    Err(e) => build_error_response(&e),
+                    Ok(values) => {
+                        self.dirty_all();
+                        let values = missing.into_iter().zip(values).collect();
+                        match exec_batch(store, transaction.namespace.clone(), batch, values) {
+                            None => build_not_stored_response(),
+                            Some((responses, written)) if written.is_empty() => {
+                                build_exec_response(&responses)
+                            }
+                            Some((responses, written)) => {
+                                let values: Vec<(String, Option<String>)> = written
+                                    .iter()
+                                    .map(|(key, _)| {
+                                        let value = store.peek(key).and_then(|entry| {
+                                            entry.value.as_str().ok().map(str::to_string)
+                                        });
+                                        (key.clone(), value)
+                                    })
+                                    .collect();
+                                let backend = backend.clone();
+                                let (namespace, loaded) = (namespace.clone(), loaded.clone());
+                                tokio::task::spawn_blocking(move || {
+                                    let written = values
+                                        .iter()
+                                        .map(|(key, value)| {
+                                            backend.write(&namespace, key, value.as_deref())
+                                        })
+                                        .map(|written| written.map(|()| None))
+                                        .collect();
+                                    let _ = loaded.send((id, written));
+                                });
+                                transaction.stage = Stage::Writing { responses, written };
+                                self.transactions.insert(id, transaction);
+                                return Vec::new();
+                            }
+                        }
+                    }
+                }
+            }
+            Stage::Writing {
+                mut responses,
+                written,
+            } => {
+                for ((key, writers), result) in written.into_iter().zip(results) {
+                    if let Err(e) = result {
+                        // the write did not reach the backend, so the cache must not keep it
+                        store.discard(&key);
+                        for index in writers {
+                            responses[index] = build_error_response(&e);
+                        }
+                    }
+                }
+                build_exec_response(&responses)
+            }
+        };
+
+        let mut waiting = Vec::new();
+        for key in transaction.keys {
+            if let Some(flight) = self.flights.remove(&(namespace.clone(), key)) {
+                waiting.extend(flight.waiting);
+            }
+        }
+        let mut responses = vec![(transaction.tag, response)];
+        for (tag, request) in waiting {
+            let response = self.serve(store, Some(backend), tag, request, loaded);
+            responses.extend(response.map(|response| (tag, response)));
+        }
+        responses
+    }
+
+    /// Like `answer_request`, but a write through to the backend runs off the request loop,
+    /// in which case the response waits for it and `None` is returned.
+    fn answer(
+        &mut self,
+        store: &mut Store,
+        backend: &BackendHandle,
+        tag: u64,
+        request: ParsedRequest,
+        loaded: &mpsc::UnboundedSender<Loaded>,
+    ) -> Option<Vec<u8>> {
+        let Some(key) = request.key.clone() else {
+            return Some(handle_request(store, request));
+        };
+        if backend.policy() != WritePolicy::Through || !writes_through(&request.cmd) {
+            return Some(answer_request(store, Some(backend), request));
+        }
+        let namespace = request_namespace(&request);
+        let response = handle_request(store, request);
+        let value = match store.peek(&key).map(|entry| entry.value.as_str()) {
+            Some(Ok(value)) => Some(value.to_string()),
+            Some(Err(_)) => return Some(response),
+            None => None,
+        };
+
+        let (backend, loaded) = (backend.clone(), loaded.clone());
+        let flight_key = (namespace.clone(), key.clone());
+        let id = self.start(flight_key, Some((tag, response)), Vec::new());
+        tokio::task::spawn_blocking(move || {
+            let written = backend.write(&namespace, &key, value.as_deref());
+            let _ = loaded.send((id, vec![written.map(|()| None)]));
+        });
+        None
+    }
+
+    fn start(
+        &mut self,
+        flight_key: (String, String),
+        writer: Option<(u64, Vec<u8>)>,
+        waiting: Vec<(u64, ParsedRequest)>,
+    ) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        let flight = Flight {
+            id,
+            writer,
+            waiting,
+            dirty: false,
+        };
+        self.flights.insert(flight_key, flight);
+        id
+    }
+
+    /// Marks every load in flight dirty, for requests that may change keys they don't name.
+    fn dirty_all(&mut self) {
+        for flight in self.flights.values_mut() {
+            flight.dirty = true;
+        }
+    }
+
+    /// Caches a finished load, or drops the key again if its write failed, and answers the
+    /// requests that waited for it, returning their responses with their tags. A waiting
+    /// request that writes through starts a new flight the rest then wait for.
+    fn finish(
+        &mut self,
+        namespaces: &mut Namespaces,
+        backend: Option<&BackendHandle>,
+        id: u64,
+        mut results: Vec<Result<Option<String>, Error>>,
+        loaded: &mpsc::UnboundedSender<Loaded>,
+    ) -> Vec<(u64, Vec<u8>)> {
+        if let Some(transaction) = self.transactions.get(&id) {
+            let store = namespaces
+                .get_mut(transaction.namespace.as_deref())
+                .expect("flights are only started for known namespaces");
+            let backend = backend.expect("transactions only wait on a backend");
+            return self.land(store, backend, id, results, loaded);
+        }
+        let result = results.pop().expect("a key's flight has a single result");
+        let Some(flight_key) = self
+            .flights
+            .iter()
+            .find(|(_, flight)| flight.id == id)
+            .map(|(flight_key, _)| flight_key.clone())
+        else {
+            return Vec::new();
+        };
+        let flight = self.flights.remove(&flight_key).unwrap();
+        let (namespace, key) = flight_key.clone();
+        let store = namespaces
+            .get_mut(Some(namespace.as_str()))
+            .expect("flights are only started for known namespaces");
+        let mut responses = Vec::new();
+        let mut failed_load = None;
+        // whether the cache now holds the backend's copy of the key, or knows it has none
+        let settled = match (flight.writer, result) {
+            (Some((tag, response)), Ok(_)) => {
+                responses.push((tag, response));
+                !flight.dirty
+            }
+            (Some((tag, _)), Err(e)) => {
+                // the write did not reach the backend, so the cache must not keep it either
+                store.discard(&key);
+                responses.push((tag, build_error_response(&e)));
+                false
+            }
+            (None, _) if flight.dirty => false,
+            (None, Ok(value)) => {
+                if let Some(value) = value.filter(|_| store.peek(&key).is_none()) {
+                    store.fill(key, value);
+                }
+                true
+            }
+            (None, Err(e)) => {
+                failed_load = Some(e);
+                true
+            }
+        };
+
+        for (tag, request) in flight.waiting {
+            if let Some(next) = self.flights.get_mut(&flight_key) {
+                next.dirty |= writes_through(&request.cmd);
+                next.waiting.push((tag, request));
+                continue;
+            }
+            let response = match (&failed_load, backend) {
+                (Some(e), _) if reads_through(&request.cmd) => Some(build_error_response(e)),
+                // a dirty flight's result is dropped, so its requests load again, and a
+                // transaction loads whatever else it needs
+                _ if !settled || request.cmd == CommandType::Exec => {
+                    self.serve(store, backend, tag, request, loaded)
+                }
+                (_, Some(backend)) => self.answer(store, backend, tag, request, loaded),
+                (_, None) => Some(answer_request(store, None, request)),
+            };
+            responses.extend(response.map(|response| (tag, response)));
+        }
+        responses
+    }
+}
+
+fn request_namespace(request: &ParsedRequest) -> String {
+    request
+        .namespace
+        .clone()
+        .unwrap_or_else(|| DEFAULT_NAMESPACE.to_string())
+}
+
+/// String writes, which are passed on to the backend.
+fn writes_through(cmd: &CommandType) -> bool {
+    matches!(
+        cmd,
+        CommandType::Set
+            | CommandType::SetNx
+            | CommandType::Cas
+            | CommandType::GetSet
+            | CommandType::Delete
+            | CommandType::Incr
+            | CommandType::Decr
+            | CommandType::IncrBy
+            | CommandType::IncrByFloat
+    )
+}
+
+/// Commands that need a string key loaded from the backend when it is not cached.
+fn reads_through(cmd: &CommandType) -> bool {
+    match cmd {
+        CommandType::Get | CommandType::LGet | CommandType::GetMeta => true,
+        CommandType::Delete => false,
+        cmd => writes_through(cmd),
+    }
+}
+
+/// Answers a request from its namespace's store. With a backend, writes to string keys are
+/// passed on to it after, so the request's key must be cached already or known to be missing
+/// from the backend too. Collections, sketches and the like only live in the store.
+fn answer_request(
+    store: &mut Store,
+    backend: Option<&BackendHandle>,
+    request: ParsedRequest,
+) -> Vec<u8> {
+    let (Some(backend), Some(key)) = (backend, request.key.clone()) else {
+        return handle_request(store, request);
+    };
+    if !writes_through(&request.cmd) {
+        return handle_request(store, request);
+    }
+    let namespace = request_namespace(&request);
+    let response = handle_request(store, request);
+    let value = match store.peek(&key).map(|entry| entry.value.as_str()) {
+        Some(Ok(value)) => Some(value.to_string()),
+        Some(Err(_)) => return response,
+        None => None,
+    };
+    match backend.write(&namespace, &key, value.as_deref()) {
+        Ok(()) => response,
+        Err(e) => {
+            // the write did not reach the backend, so the cache must not keep it either
+            store.discard(&key);
+            build_error_response(&e)
+        }
+    }
+}
+
+fn handle_request(store: &mut Store, request: ParsedRequest) -> Vec<u8> {
+    let not_enough_args = || build_error_response(&Error::from_code(ErrorCode::NotEnoughArgs));
+    match request.cmd {
+        CommandType::Get => match request.key {
+            Some(key) => match read_entry(store, &key) {
+                Ok(Some(response)) => response,
+                Ok(None) => build_miss_response(&key),
+                Err(e) => build_error_response(&e),
+            },
+            None => not_enough_args(),
+        },
+        CommandType::LGet => match request.key {
+            Some(key) => {
+                lease_get(store, &key, &request.args).unwrap_or_else(|e| build_error_response(&e))
+            }
+            None => not_enough_args(),
+        },
+        CommandType::GetMeta => match request.key {
+            Some(key) => match store.peek(&key) {
+                Some(entry) => build_meta_response(&key, entry),
+                None => build_miss_response(&key),
+            },
+            None => not_enough_args(),
+        },
+        CommandType::Lsd => build_lsd_response(store.iter()),
+        CommandType::DbSize => build_value_response(&store.len().to_string()),
+        CommandType::Evictions => build_value_response(&store.evictions().to_string()),
+        CommandType::Invalidate if request.args.is_empty() => not_enough_args(),
+        CommandType::Invalidate => {
+            let removed: usize = request.args.iter().map(|tag| store.invalidate(tag)).sum();
+            build_value_response(&removed.to_string())
+        }
+        CommandType::Flush => {
+            let removed = store.flush(request.args.first().map(String::as_str));
+            build_value_response(&removed.to_string())
+        }
+        CommandType::Watch => {
+            let versions: Vec<String> = request
+                .args
+                .iter()
+                .map(|key| store.peek(key).map_or(0, |entry| entry.version).to_string())
+                .collect();
+            build_array_response(&versions)
+        }
+        CommandType::Scan => handle_scan(store, &request.args),
+        CommandType::Set => match (request.key, request.value) {
+            (Some(key), Some(value)) => {
+                let (value, options) = match split_set_options(&value) {
+                    Ok(split) => split,
+                    Err(e) => return build_error_response(&e),
+                };
+                let stored = match (options.lease, options.condition) {
+                    (Some(token), _) => store.fill_lease(key.clone(), value.to_string(), token),
+                    (None, Some(condition)) => {
+                        store.set_if(key.clone(), value.to_string(), condition)
+                    }
+                    (None, None) => Some(store.set(key.clone(), value.to_string())),
+                };
+                match stored {
+                    Some(_) => {
+                        store.tag(&key, options.tags);
+                        store.expire(&key, options.soft_ttl, options.ttl);
+                        build_ok_response()
+                    }
+                    None => build_not_stored_response(),
+                }
+            }
+            _ => not_enough_args(),
+        },
+        CommandType::SetNx => match (request.key, request.value) {
+            (Some(key), Some(value)) => conditional_set(store, key, value, SetCondition::IfAbsent),
+            _ => not_enough_args(),
+        },
+        CommandType::Cas => match (request.key, request.value) {
+            (Some(key), Some(value)) => match parse_arg::<u64>(&request.args) {
+                Ok(version) => conditional_set(store, key, value, SetCondition::IfVersion(version)),
+                Err(e) => build_error_response(&e),
+            },
+            _ => not_enough_args(),
+        },
+        CommandType::GetSet => match (request.key, request.value) {
+            (Some(key), Some(value)) => match store.get_set(key.clone(), value) {
+                Ok(Some(old)) => build_value_response(&old),
+                Ok(None) => build_miss_response(&key),
+                Err(e) => build_error_response(&e),
+            },
+            _ => not_enough_args(),
+        },
+        CommandType::Delete => match request.key {
+            Some(key) => {
+                store.delete(&key);
+                build_ok_response()
+            }
+            None => not_enough_args(),
+        },
+        CommandType::Lock | CommandType::Unlock | CommandType::Extend => match request.key {
+            Some(key) => handle_lock(store, &request.cmd, &key, &request.args)
+                .unwrap_or_else(|e| build_error_response(&e)),
+            None => not_enough_args(),
+        },
+        CommandType::PfAdd
+        | CommandType::PfCount
+        | CommandType::PfMerge
+        | CommandType::PfDump
+        | CommandType::PfLoad
+        | CommandType::BfAdd
+        | CommandType::BfExists => match request.key {
+            Some(ref key) => handle_sketch(store, &request.cmd, key, &request.args)
+                .unwrap_or_else(|e| build_error_response(&e)),
+            None => not_enough_args(),
+        },
+        CommandType::Throttle => match request.key {
+            Some(key) => handle_throttle(store, &key, &request.args)
+                .unwrap_or_else(|e| build_error_response(&e)),
+            None => not_enough_args(),
+        },
+        CommandType::Incr | CommandType::Decr | CommandType::IncrBy => {
+            let Some(key) = request.key else {
+                return not_enough_args();
+            };
+            let delta = match request.cmd {
+                CommandType::Incr => Ok(1),
+                CommandType::Decr => Ok(-1),
+                _ => parse_arg::<i64>(&request.args),
+            };
+            match delta.and_then(|delta| store.incr_by(&key, delta)) {
+                Ok(value) => build_value_response(&value.to_string()),
+                Err(e) => build_error_response(&e),
+            }
+        }
+        CommandType::IncrByFloat => {
+            let Some(key) = request.key else {
+                return not_enough_args();
+            };
+            match parse_arg::<f64>(&request.args).and_then(|delta| store.incr_by_float(&key, delta))
+            {
+                Ok(value) => build_value_response(&value.to_string()),
+                Err(e) => build_error_response(&e),
+            }
+        }
+        CommandType::HSet
+        | CommandType::HGet
+        | CommandType::HDel
+        | CommandType::HGetAll
+        | CommandType::LPush
+        | CommandType::RPush
+        | CommandType::LPop
+        | CommandType::LRange
+        | CommandType::SAdd
+        | CommandType::SRem
+        | CommandType::SMembers
+        | CommandType::SIsMember
+        | CommandType::ZAdd
+        | CommandType::ZRem
+        | CommandType::ZScore
+        | CommandType::ZRange
+        | CommandType::ZRank => match request.key {
+            Some(key) => handle_collection(store, &request.cmd, &key, &request.args)
+                .unwrap_or_else(|e| build_error_response(&e)),
+            None => not_enough_args(),
+        },
+        _ => build_error_response(&Error::from_code(ErrorCode::InvalidRequestCmd)),
+    }
+}
+
+fn purge_expired(namespaces: &mut Namespaces) {
+    for (_, store) in namespaces.iter_mut() {
+        store.purge_expired();
+    }
+}
+
+/// `FLUSHALL` empties every namespace, unlike `FLUSH` which only empties the request's own.
+fn flush_all(namespaces: &mut Namespaces) -> Vec<u8> {
+    let removed: usize = namespaces
+        .iter_mut()
+        .map(|(_, store)| store.flush(None))
+        .sum();
+    build_value_response(&removed.to_string())
+}
+
+/// Runs a transaction forwarded by the master, first caching the values loaded from the
+/// backend for the keys it reads through. If any watched key changed since WATCH, nothing runs
+/// and the answer is `None`. Requests run back to back on the request loop, so no other
+/// request can interleave with them. Returns their responses and the keys they wrote, to be
+/// passed on to the backend.
+fn exec_batch(
+    store: &mut Store,
+    namespace: Option<String>,
+    batch: ExecBatch,
+    loaded: Vec<(String, Option<String>)>,
+) -> Option<(Vec<Vec<u8>>, Written)> {
+    let unchanged = batch
+        .watches
+        .iter()
+        .all(|(key, version)| store.peek(key).map_or(0, |entry| entry.version) == *version);
+    if !unchanged {
+        return None;
+    }
+    for (key, value) in loaded {
+        if let Some(value) = value.filter(|_| store.peek(&key).is_none()) {
+            store.fill(key, value);
+        }
+    }
+    let mut written = Written::new();
+    let mut responses = Vec::with_capacity(batch.requests.len());
+    for (index, request) in batch.requests.into_iter().enumerate() {
+        let request = match parse_request(request.into_bytes()) {
+            Ok(request) => request,
+            Err(e) => {
+                responses.push(build_error_response(&e));
+                continue;
+            }
+        };
+        if let Some(key) = request.key.clone().filter(|_| writes_through(&request.cmd)) {
+            match written.iter_mut().find(|(written, _)| *written == key) {
+                Some((_, writers)) => writers.push(index),
+                None => written.push((key, vec![index])),
+            }
+        }
+        // the batch's requests are sent without the namespace prefix of the batch itself
+        let request = ParsedRequest {
+            namespace: namespace.clone(),
+            ..request
+        };
+        responses.push(handle_request(store, request));
+    }
+    // collections and the like are not passed on
+    written.retain(|(key, _)| {
+        !matches!(
+            store.peek(key).map(|entry| entry.value.as_str()),
+            Some(Err(_))
+        )
+    });
+    Some((responses, written))
+}
+
+/// The keys a transaction's requests read through, for loading them before it runs.
+fn read_through_keys(batch: &ExecBatch) -> Vec<String> {
+    let mut keys: Vec<String> = batch
+        .requests
+        .iter()
+        .filter_map(|request| parse_request(request.clone().into_bytes()).ok())
+        .filter(|request| reads_through(&request.cmd))
+        .filter_map(|request| request.key)
+        .collect();
+    keys.sort();
+    keys.dedup();
+    keys
+}
+
+/// Hash, list, set and sorted set commands. Counts are answered with `VAL <n>`, single elements with
+/// `VAL <element>` or `MSS <key>`, and multiple elements with `ARR`.
+fn handle_collection(
+    store: &mut Store,
+    cmd: &CommandType,
+    key: &str,
+    args: &[String],
+) -> Result<Vec<u8>, Error> {
+    let count = |n: usize| build_value_response(&n.to_string());
+    let element = |element: Option<String>| match element {
+        Some(element) => build_value_response(&element),
+        None => build_miss_response(key),
+    };
+    let min_args = if matches!(
+        cmd,
+        CommandType::HGetAll | CommandType::LPop | CommandType::SMembers
+    ) {
+        0
+    } else if matches!(
+        cmd,
+        CommandType::HSet | CommandType::LRange | CommandType::ZAdd | CommandType::ZRange
+    ) {
+        2
+    } else {
+        1
+    };
+    if args.len() < min_args {
+        return Err(Error::from_code(ErrorCode::NotEnoughArgs));
+    }
+
+    match cmd {
+        CommandType::HSet => {
+            if !args.len().is_multiple_of(2) {
+                return Err(Error::from_code(ErrorCode::NotEnoughArgs));
+            }
+            let pairs = args
+                .chunks(2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect();
+            Ok(count(store.hset(key, pairs)?))
+        }
+        CommandType::HGet => Ok(element(store.hget(key, &args[0])?)),
+        CommandType::HDel => Ok(count(store.hdel(key, args)?)),
+        CommandType::HGetAll => {
+            let items: Vec<String> = store
+                .hgetall(key)?
+                .into_iter()
+                .flat_map(|(field, value)| [field, value])
+                .collect();
+            Ok(build_array_response(&items))
+        }
+        CommandType::LPush | CommandType::RPush => Ok(count(store.push(
+            key,
+            args.to_vec(),
+            *cmd == CommandType::LPush,
+        )?)),
+        CommandType::LPop => Ok(element(store.lpop(key)?)),
+        CommandType::LRange => {
+            let index = |arg: &String| {
+                arg.parse::<i64>()
+                    .map_err(|_| Error::from_code(ErrorCode::InvalidRequestArg))
+            };
+            let items = store.lrange(key, index(&args[0])?, index(&args[1])?)?;
+            Ok(build_array_response(&items))
+        }
+        CommandType::SAdd => Ok(count(store.sadd(key, args.to_vec())?)),
+        CommandType::SRem => Ok(count(store.srem(key, args)?)),
+        CommandType::SMembers => Ok(build_array_response(&store.smembers(key)?)),
+        CommandType::SIsMember => Ok(count(store.sismember(key, &args[0])? as usize)),
+        CommandType::ZAdd => {
+            if !args.len().is_multiple_of(2) {
+                return Err(Error::from_code(ErrorCode::NotEnoughArgs));
+            }
+            let members = args
+                .chunks(2)
+                .map(|pair| match pair[0].parse::<f64>() {
+                    Ok(score) if !score.is_nan() => Ok((score, pair[1].clone())),
+                    _ => Err(Error::from_code(ErrorCode::NotNumeric)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(count(store.zadd(key, members)?))
+        }
+        CommandType::ZRem => Ok(count(store.zrem(key, args)?)),
+        CommandType::ZScore => Ok(element(
+            store.zscore(key, &args[0])?.map(|score| score.to_string()),
+        )),
+        CommandType::ZRank => Ok(element(
+            store.zrank(key, &args[0])?.map(|rank| rank.to_string()),
+        )),
+        // ZRANGE key start stop [BYSCORE] [WITHSCORES]
+        CommandType::ZRange => {
+            let flags: Vec<String> = args[2..].iter().map(|a| a.to_uppercase()).collect();
+            if flags.iter().any(|f| f != "BYSCORE" && f != "WITHSCORES") {
+                return Err(Error::from_code(ErrorCode::InvalidRequestArg));
+            }
+            let range = if flags.iter().any(|f| f == "BYSCORE") {
+                let (min, max) = (parse_score_bound(&args[0])?, parse_score_bound(&args[1])?);
+                store.zrange_by_score(key, min, max)?
+            } else {
+                let index = |arg: &String| {
+                    arg.parse::<i64>()
+                        .map_err(|_| Error::from_code(ErrorCode::InvalidRequestArg))
+                };
+                store.zrange_by_rank(key, index(&args[0])?, index(&args[1])?)?
+            };
+            let with_scores = flags.iter().any(|f| f == "WITHSCORES");
+            let items: Vec<String> = range
+                .into_iter()
+                .flat_map(|(member, score)| {
+                    let score = with_scores.then(|| score.to_string());
+                    std::iter::once(member).chain(score)
+                })
+                .collect();
+            Ok(build_array_response(&items))
+        }
+        _ => Err(Error::from_code(ErrorCode::InvalidRequestCmd)),
+    }
+}
+
+/// `LGET key [lease_ms]` answers hits like `GET`. The first client to miss gets `LSE` with a
+/// lease token to pass to `SET ... LEASE <token>`, and later ones get `WAI` until the key is
+/// filled or the lease runs out.
+fn lease_get(store: &mut Store, key: &str, args: &[String]) -> Result<Vec<u8>, Error> {
+    let ttl = match args.first() {
+        Some(_) => match parse_arg::<u64>(args)? {
+            0 => return Err(Error::from_code(ErrorCode::InvalidRequestArg)),
+            ms => Duration::from_millis(ms),
+        },
+        None => DEFAULT_LEASE,
+    };
+    if let Some(response) = read_entry(store, key)? {
+        return Ok(response);
+    }
+    Ok(match store.lease(key, ttl) {
+        Some(token) => build_lease_response(key, token),
+        None => build_wait_response(key),
+    })
+}
+
+/// Answers a read of `key` with `HIT`, or with `STL` once the value is past its soft expiry,
+/// handing the first client to see it stale a lease to refresh it. `None` on a miss.
+fn read_entry(store: &mut Store, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    let Some(entry) = store.get(key) else {
+        return Ok(None);
+    };
+    let (version, stale) = (entry.version, entry.is_stale(Instant::now()));
+    let value = entry.value.as_str()?.to_string();
+    if !stale {
+        return Ok(Some(build_hit_response(key, version, &value)));
+    }
+    let lease = store.lease(key, DEFAULT_LEASE).unwrap_or(0);
+    Ok(Some(build_stale_response(key, version, lease, &value)))
+}
+
+fn conditional_set(
+    store: &mut Store,
+    key: String,
+    value: String,
+    condition: SetCondition,
+) -> Vec<u8> {
+    match store.set_if(key, value, condition) {
+        Some(_) => build_ok_response(),
+        None => build_not_stored_response(),
+    }
+}
+
+/// Distributed locks with leases. `LOCK key client_id lease_ms` and
+/// `EXTEND key client_id lease_ms` answer with the fencing token, `UNLOCK key client_id`
+/// with `OK`. `LOCK` answers `NST` while another client holds the lock.
+fn handle_lock(
+    store: &mut Store,
+    cmd: &CommandType,
+    key: &str,
+    args: &[String],
+) -> Result<Vec<u8>, Error> {
+    let owner = args
+        .first()
+        .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))?;
+    let lease = || match parse_arg::<u64>(&args[1..])? {
+        0 => Err(Error::from_code(ErrorCode::InvalidRequestArg)),
+        ms => Ok(Duration::from_millis(ms)),
+    };
+    match cmd {
+        CommandType::Lock => Ok(match store.lock(key, owner, lease()?) {
+            Some(token) => build_value_response(&token.to_string()),
+            None => build_not_stored_response(),
+        }),
+        CommandType::Extend => {
+            let token = store.extend(key, owner, lease()?)?;
+            Ok(build_value_response(&token.to_string()))
+        }
+        _ => store.unlock(key, owner).map(|_| build_ok_response()),
+    }
+}
+
+/// HyperLogLog and Bloom filter commands. `PFCOUNT` and `PFMERGE` only see keys held by this
+/// partition; the master merges keys spread over several partitions with `PFDUMP` and `PFLOAD`.
+fn handle_sketch(
+    store: &mut Store,
+    cmd: &CommandType,
+    key: &str,
+    args: &[String],
+) -> Result<Vec<u8>, Error> {
+    let flag = |set: bool| build_value_response(if set { "1" } else { "0" });
+    let first_arg = || {
+        args.first()
+            .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))
+    };
+    match cmd {
+        CommandType::PfAdd => Ok(flag(store.pfadd(key, args)?)),
+        CommandType::PfCount => {
+            let keys: Vec<&str> = std::iter::once(key)
+                .chain(args.iter().map(String::as_str))
+                .collect();
+            let count = store.pfunion(&keys)?.map_or(0, |hll| hll.count());
+            Ok(build_value_response(&count.to_string()))
+        }
+        CommandType::PfMerge => {
+            let sources: Vec<&str> = args.iter().map(String::as_str).collect();
+            let union = store.pfunion(&sources)?.unwrap_or_default();
+            store.pfmerge(key, &union)?;
+            Ok(build_ok_response())
+        }
+        CommandType::PfDump => Ok(match store.pfunion(&[key])? {
+            Some(hll) => build_value_response(&hll.to_hex()),
+            None => build_miss_response(key),
+        }),
+        CommandType::PfLoad => {
+            store.pfmerge(key, &HyperLogLog::from_hex(first_arg()?)?)?;
+            Ok(build_ok_response())
+        }
+        CommandType::BfAdd => Ok(flag(store.bf_add(key, first_arg()?)?)),
+        _ => Ok(flag(store.bf_exists(key, first_arg()?)?)),
+    }
+}
+
+/// `THROTTLE key max_burst count_per_period period_secs [quantity]` answers with an `ARR` of
+/// `allowed` or `denied`, the limit, the remaining quota, the milliseconds until the request
+/// could be retried (`-1` when allowed, or when it never can be) and the milliseconds until
+/// the key is back to its full limit.
+fn handle_throttle(store: &mut Store, key: &str, args: &[String]) -> Result<Vec<u8>, Error> {
+    if args.len() < 3 {
+        return Err(Error::from_code(ErrorCode::NotEnoughArgs));
+    }
+    let count = |idx: usize, min: u64| match parse_arg::<u64>(&args[idx..])? {
+        n if n >= min && n < u32::MAX as u64 => Ok(n),
+        _ => Err(Error::from_code(ErrorCode::InvalidRequestArg)),
+    };
+    let limit = RateLimit {
+        max_burst: count(0, 0)?,
+        count_per_period: count(1, 1)?,
+        period: Duration::from_secs(count(2, 1)?),
+    };
+    let quantity = if args.len() > 3 { count(3, 0)? } else { 1 };
+
+    let result = store.throttle(key, &limit, quantity);
+    let millis = |duration: Option<Duration>| match duration {
+        Some(duration) => duration.as_millis().to_string(),
+        None => "-1".to_string(),
+    };
+    Ok(build_array_response(&[
+        if result.allowed { "allowed" } else { "denied" }.to_string(),
+        result.limit.to_string(),
+        result.remaining.to_string(),
+        millis(result.retry_after),
+        millis(Some(result.reset_after)),
+    ]))
+}
+
+/// Parses the first argument after the key.
+fn parse_arg<T: FromStr>(args: &[String]) -> Result<T, Error> {
+    args.first()
+        .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))?
+        .parse()
+        .map_err(|_| Error::from_code(ErrorCode::NotNumeric))
+}
+
+/// Partition side of `SCAN`. The cursor is the last key returned, hex-encoded, or `0`
+/// at the start and end of the scan.
+fn handle_scan(store: &Store, args: &[String]) -> Vec<u8> {
+    let scan = match parse_scan_args(args) {
+        Ok(scan) => scan,
+        Err(e) => return build_error_response(&e),
+    };
+    let after = if scan.cursor == "0" {
+        None
+    } else {
+        match decode_cursor_key(&scan.cursor) {
+            Ok(key) => Some(key),
+            Err(e) => return build_error_response(&e),
+        }
+    };
+
+    let (keys, more) = store.scan(after.as_deref(), scan.pattern.as_deref(), scan.count);
+    let cursor = match keys.last() {
+        Some(last) if more => encode_cursor_key(last),
+        _ => "0".to_string(),
+    };
+    build_scan_response(&cursor, &keys)
+}
+
+/// Connects the stream that carries this partition's key events to the master.
+async fn open_event_stream(master_addr: &str, token: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(master_addr).await.unwrap();
+    stream
+        .write_all(&build_event_stream_request(token))
+        .await
+        .unwrap();
+    let mut buf = [0; 4096];
+    match stream.read(&mut buf).await {
+        Ok(_) => match parse_request(buf.to_vec()) {
+            Ok(ParsedRequest {
+                cmd: CommandType::Ack,
+                ..
+            }) => event!(Level::INFO, "Opened event stream to master."),
+            response => {
+                event!(
+                    Level::ERROR,
+                    "Failed to open event stream. Received unexpected response: {:?}",
+                    response
+                );
+                panic!("Failed to open event stream");
+            }
+        },
+        Err(e) => {
+            event!(Level::ERROR, "Failed to read from socket: {:?}", e);
+            panic!("Failed to open event stream");
+        }
+    }
+    stream
+}
+
+/// Sends the key events recorded while serving the last request to the master, each tagged
+/// with the namespace of its key.
+async fn publish_events(stream: &mut TcpStream, namespaces: &mut Namespaces) {
+    let mut frames = Vec::new();
+    for (name, store) in namespaces.iter_mut() {
+        let namespace = Some(name).filter(|name| *name != DEFAULT_NAMESPACE);
+        for (event, key) in store.take_events() {
+            frames.extend(build_namespaced_request(
+                namespace,
+                &build_event_request(event, &key),
+            ));
+        }
+    }
+    if frames.is_empty() {
+        return;
+    }
+    if let Err(e) = stream.write_all(&frames).await {
+        event!(Level::ERROR, "Failed to send key events to master: {:?}", e);
+    }
 }
 
-async fn notify_master(stream: &mut TcpStream) {
-    stream.write_all(&build_notify_request()).await.unwrap();
+/// Registers with the master, returning the token to open the event stream with.
+async fn notify_master(stream: &mut TcpStream, weight: u32) -> String {
+    stream
+        .write_all(&build_notify_request(weight))
+        .await
+        .unwrap();
 
     let mut buf = [0; 4096];
     match stream.read(&mut buf).await {
         Ok(_) => match parse_request(buf.to_vec()) {
             Ok(parsed_request) => {
                 event!(Level::DEBUG, "Parsed notify response: {:?}", parsed_request);
-                match parsed_request.cmd {
-                    CommandType::Ack => {
+                match (&parsed_request.cmd, parsed_request.args.as_slice()) {
+                    (CommandType::Ack, [token]) => {
                         event!(
                             Level::INFO,
                             "Successfully connected to master. Listening for commands."
                         );
+                        token.clone()
                     }
                     _ => {
                         event!(
@@ -0,0 +1,278 @@
+use crate::glob::glob_match;
+use crate::store::Entry;
+use crate::value::Value;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Namespace and key of a spilled entry.
+type SpillKey = (String, String);
+
+/// Length of a record's header: the lengths of its namespace, key and value.
+const HEADER_LEN: u64 = 12;
+
+/// A second tier for entries evicted from memory. Values are appended to a log file, each
+/// as a record of the namespace, key and value lengths followed by the three themselves,
+/// while the rest of the entry stays in an index in memory. The log is rewritten without
+/// the records of entries taken back or replaced once it would outgrow its cap, and the
+/// oldest entries are dropped when even the live ones would. The log starts out empty, so
+/// entries spilled before a restart are gone.
+pub struct DiskTier {
+    path: PathBuf,
+    log: File,
+    index: HashMap<SpillKey, Slot>,
+    /// Spilled keys, oldest first, with the sequence number of their spill. Keys taken back
+    /// or spilled again since are skipped when the oldest entries are dropped.
+    order: VecDeque<(u64, SpillKey)>,
+    next_seq: u64,
+    /// Bytes in the log, including records no longer in the index.
+    log_bytes: u64,
+    /// Bytes of the records in the index.
+    live_bytes: u64,
+    max_bytes: u64,
+}
+
+struct Slot {
+    seq: u64,
+    /// Start and length of the record in the log.
+    offset: u64,
+    len: u64,
+    /// The entry without its value, which is in the log.
+    entry: Entry,
+}
+
+impl DiskTier {
+    /// Opens the log at `path`, emptying it, with room for `max_bytes` of records.
+    pub fn open(path: impl Into<PathBuf>, max_bytes: u64) -> io::Result<Self> {
+        let path = path.into();
+        let log = open_log(&path)?;
+        Ok(Self {
+            path,
+            log,
+            index: HashMap::new(),
+            order: VecDeque::new(),
+            next_seq: 0,
+            log_bytes: 0,
+            live_bytes: 0,
+            max_bytes,
+        })
+    }
+
+    /// Number of entries on disk.
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Bytes taken by the entries on disk.
+    pub fn bytes(&self) -> u64 {
+        self.live_bytes
+    }
+
+    /// Writes an evicted entry to disk, replacing any older copy of the key. Only strings
+    /// are spilled; other values, and strings too large for the tier, are dropped and
+    /// `false` is returned.
+    pub fn spill(&mut self, namespace: &str, key: &str, mut entry: Entry) -> io::Result<bool> {
+        let id = (namespace.to_string(), key.to_string());
+        self.remove(&id);
+        let Value::Str(value) = std::mem::replace(&mut entry.value, Value::Str(String::new()))
+        else {
+            return Ok(false);
+        };
+        let record = encode_record(namespace, key, &value);
+        let len = record.len() as u64;
+        if len > self.max_bytes {
+            return Ok(false);
+        }
+        while self.live_bytes + len > self.max_bytes {
+            self.drop_oldest();
+        }
+        if self.log_bytes + len > self.max_bytes {
+            self.compact()?;
+        }
+        self.log.seek(SeekFrom::Start(self.log_bytes))?;
+        self.log.write_all(&record)?;
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.push_back((seq, id.clone()));
+        self.index.insert(
+            id,
+            Slot {
+                seq,
+                offset: self.log_bytes,
+                len,
+                entry,
+            },
+        );
+        self.log_bytes += len;
+        self.live_bytes += len;
+        Ok(true)
+    }
+
+    /// Takes `key` off disk, returning its entry as it was spilled.
+    pub fn take(&mut self, namespace: &str, key: &str) -> io::Result<Option<Entry>> {
+        let id = (namespace.to_string(), key.to_string());
+        let Some(slot) = self.index.remove(&id) else {
+            return Ok(None);
+        };
+        self.live_bytes -= slot.len;
+        let value_offset = HEADER_LEN + (namespace.len() + key.len()) as u64;
+        let mut value = vec![0; (slot.len - value_offset) as usize];
+        self.log.seek(SeekFrom::Start(slot.offset + value_offset))?;
+        self.log.read_exact(&mut value)?;
+        let value =
+            String::from_utf8(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut entry = slot.entry;
+        entry.value = Value::Str(value);
+        Ok(Some(entry))
+    }
+
+    /// Drops the keys of `namespace` matching `pattern`, or all of them, returning how many
+    /// were dropped.
+    pub fn flush(&mut self, namespace: &str, pattern: Option<&str>) -> usize {
+        self.remove_where(|(ns, key), _| {
+            ns == namespace && pattern.is_none_or(|pattern| glob_match(pattern, key))
+        })
+    }
+
+    /// Drops the keys of `namespace` tagged with `tag`, returning how many were dropped.
+    pub fn invalidate(&mut self, namespace: &str, tag: &str) -> usize {
+        self.remove_where(|(ns, _), entry| ns == namespace && entry.tags.iter().any(|t| t == tag))
+    }
+
+    /// Drops every key.
+    pub fn clear(&mut self) -> usize {
+        self.remove_where(|_, _| true)
+    }
+
+    fn remove_where(&mut self, mut doomed: impl FnMut(&SpillKey, &Entry) -> bool) -> usize {
+        let ids: Vec<SpillKey> = self
+            .index
+            .iter()
+            .filter(|(id, slot)| doomed(id, &slot.entry))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in &ids {
+            self.remove(id);
+        }
+        ids.len()
+    }
+
+    fn remove(&mut self, id: &SpillKey) {
+        if let Some(slot) = self.index.remove(id) {
+            self.live_bytes -= slot.len;
+        }
+    }
+
+    fn drop_oldest(&mut self) {
+        while let Some((seq, id)) = self.order.pop_front() {
+            if self.index.get(&id).is_some_and(|slot| slot.seq == seq) {
+                self.remove(&id);
+                return;
+            }
+        }
+    }
+
+    /// Rewrites the log with only the records in the index.
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp = self.path.with_extension("compacting");
+        let mut compacted = open_log(&tmp)?;
+        let mut slots: Vec<(&SpillKey, &Slot)> = self.index.iter().collect();
+        slots.sort_by_key(|(_, slot)| slot.offset);
+        // the index keeps pointing into the old log until the new one has replaced it
+        let mut moved = Vec::with_capacity(slots.len());
+        let mut offset = 0;
+        for (id, slot) in slots {
+            let mut record = vec![0; slot.len as usize];
+            self.log.seek(SeekFrom::Start(slot.offset))?;
+            self.log.read_exact(&mut record)?;
+            compacted.write_all(&record)?;
+            moved.push((id.clone(), offset));
+            offset += slot.len;
+        }
+        fs::rename(&tmp, &self.path)?;
+        for (id, offset) in moved {
+            if let Some(slot) = self.index.get_mut(&id) {
+                slot.offset = offset;
+            }
+        }
+        self.log = compacted;
+        self.log_bytes = offset;
+        self.order
+            .retain(|(seq, id)| self.index.get(id).is_some_and(|slot| slot.seq == *seq));
+        Ok(())
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+fn encode_record(namespace: &str, key: &str, value: &str) -> Vec<u8> {
+    let mut record =
+        Vec::with_capacity(HEADER_LEN as usize + namespace.len() + key.len() + value.len());
+    for part in [namespace, key, value] {
+        record.extend_from_slice(&(part.len() as u32).to_le_bytes());
+    }
+    for part in [namespace, key, value] {
+        record.extend_from_slice(part.as_bytes());
+    }
+    record
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::Store;
+    use std::num::NonZeroUsize;
+
+    fn evicted(value: &str, version: u64) -> Entry {
+        let mut store = Store::new(NonZeroUsize::new(1).unwrap());
+        store.keep_evicted();
+        store.set("k".to_string(), value.to_string());
+        store.set("other".to_string(), String::new());
+        let (_, mut entry) = store.take_evicted().pop().unwrap();
+        entry.version = version;
+        entry
+    }
+
+    #[test]
+    fn spilled_entries_come_back_and_the_oldest_go_first() {
+        let path = std::env::temp_dir().join(format!("hitormiss-{}.log", uuid::Uuid::new_v4()));
+        // room for three of the 21-byte records below
+        let mut disk = DiskTier::open(&path, 64).unwrap();
+        assert!(disk.spill("default", "a", evicted("1", 1)).unwrap());
+        assert!(disk.spill("billing", "a", evicted("2", 2)).unwrap());
+        assert!(disk.spill("default", "b", evicted("3", 3)).unwrap());
+        assert!(!disk
+            .spill("default", "c", evicted(&"x".repeat(64), 4))
+            .unwrap());
+
+        let entry = disk.take("billing", "a").unwrap().unwrap();
+        assert_eq!(entry.value, Value::Str("2".to_string()));
+        assert_eq!(entry.version, 2);
+        assert!(disk.take("billing", "a").unwrap().is_none());
+
+        // fits once the taken record is compacted away, then pushes out the oldest entry
+        assert!(disk.spill("default", "d", evicted("4", 5)).unwrap());
+        assert!(disk.spill("default", "e", evicted("5", 6)).unwrap());
+        assert_eq!(disk.len(), 3);
+        assert!(disk.take("default", "a").unwrap().is_none());
+        let entry = disk.take("default", "b").unwrap().unwrap();
+        assert_eq!(entry.value, Value::Str("3".to_string()));
+
+        assert_eq!(disk.flush("default", Some("d*")), 1);
+        assert_eq!(disk.clear(), 1);
+        assert_eq!(disk.bytes(), 0);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -12,6 +12,19 @@ pub static ERR_UNSUPPORTED_MASTER: &str =
 pub static ERR_UNSUPPORTED_PARTITION: &str =
     "Internal error: this command is not supported on this node (partition)";
 pub static ERR_PARTITION_UNAVAILABLE: &str = "Internal error: partition unavailable";
+pub static ERR_NOT_NUMERIC: &str = "Invalid request: value is not a valid number";
+pub static ERR_NUMERIC_OVERFLOW: &str = "Invalid request: increment would overflow";
+pub static ERR_WRONG_TYPE: &str =
+    "Invalid request: operation against a key holding the wrong kind of value";
+pub static ERR_SUBSCRIBED: &str =
+    "Invalid request: only (P|K)SUBSCRIBE and (P|K)UNSUBSCRIBE are allowed while subscribed";
+pub static ERR_CROSS_PARTITION: &str =
+    "Invalid request: transaction keys span multiple partitions, use a hash tag to group them";
+pub static ERR_TRANSACTION_STATE: &str =
+    "Invalid request: command not allowed in the current transaction state";
+pub static ERR_NOT_LOCK_HOLDER: &str = "Invalid request: lock is not held by this client";
+pub static ERR_UNKNOWN_NAMESPACE: &str = "Invalid request: namespace not found";
+pub static ERR_BACKEND: &str = "Internal error: backend request failed";
 
 #[derive(Debug, Clone)]
 pub struct Error {
@@ -31,6 +44,15 @@ pub enum ErrorCode {
     UnsupportedCommandPartition = 8,
     PartitionUnavailable = 9,
     Unknown = 10,
+    NotNumeric = 11,
+    NumericOverflow = 12,
+    WrongType = 13,
+    Subscribed = 14,
+    CrossPartition = 15,
+    TransactionState = 16,
+    NotLockHolder = 17,
+    UnknownNamespace = 18,
+    Backend = 19,
 }
 
 impl ErrorCode {
@@ -45,6 +67,15 @@ impl ErrorCode {
             7 => ErrorCode::UnsupportedCommandMaster,
             8 => ErrorCode::UnsupportedCommandPartition,
             9 => ErrorCode::PartitionUnavailable,
+            11 => ErrorCode::NotNumeric,
+            12 => ErrorCode::NumericOverflow,
+            13 => ErrorCode::WrongType,
+            14 => ErrorCode::Subscribed,
+            15 => ErrorCode::CrossPartition,
+            16 => ErrorCode::TransactionState,
+            17 => ErrorCode::NotLockHolder,
+            18 => ErrorCode::UnknownNamespace,
+            19 => ErrorCode::Backend,
             _ => ErrorCode::Unknown,
         }
     }
@@ -60,6 +91,15 @@ impl ErrorCode {
             ErrorCode::UnsupportedCommandPartition => 8,
             ErrorCode::PartitionUnavailable => 9,
             ErrorCode::Unknown => 10,
+            ErrorCode::NotNumeric => 11,
+            ErrorCode::NumericOverflow => 12,
+            ErrorCode::WrongType => 13,
+            ErrorCode::Subscribed => 14,
+            ErrorCode::CrossPartition => 15,
+            ErrorCode::TransactionState => 16,
+            ErrorCode::NotLockHolder => 17,
+            ErrorCode::UnknownNamespace => 18,
+            ErrorCode::Backend => 19,
         }
     }
 }
@@ -83,6 +123,15 @@ impl Error {
             ErrorCode::UnsupportedCommandPartition => ERR_UNSUPPORTED_PARTITION.to_string(),
             ErrorCode::PartitionUnavailable => ERR_PARTITION_UNAVAILABLE.to_string(),
             ErrorCode::Unknown => ERR_UNKNOWN.to_string(),
+            ErrorCode::NotNumeric => ERR_NOT_NUMERIC.to_string(),
+            ErrorCode::NumericOverflow => ERR_NUMERIC_OVERFLOW.to_string(),
+            ErrorCode::WrongType => ERR_WRONG_TYPE.to_string(),
+            ErrorCode::Subscribed => ERR_SUBSCRIBED.to_string(),
+            ErrorCode::CrossPartition => ERR_CROSS_PARTITION.to_string(),
+            ErrorCode::TransactionState => ERR_TRANSACTION_STATE.to_string(),
+            ErrorCode::NotLockHolder => ERR_NOT_LOCK_HOLDER.to_string(),
+            ErrorCode::UnknownNamespace => ERR_UNKNOWN_NAMESPACE.to_string(),
+            ErrorCode::Backend => ERR_BACKEND.to_string(),
         };
 
         Error { code, msg }
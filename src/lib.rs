@@ -1,2 +1,10 @@
+pub mod backend;
+pub mod disk;
 pub mod error;
+pub mod glob;
 pub mod parser;
+pub mod placement;
+pub mod sketch;
+pub mod store;
+pub mod throttle;
+pub mod value;
@@ -1,31 +1,203 @@
 use crate::error::Error;
 use crate::error::ErrorCode;
-use lru::LruCache;
+use crate::store::{Entry, KeyEvent, SetCondition};
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::ops::Bound;
 use std::str;
+use std::time::Duration;
 
 // Add CommandType enum
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum CommandType {
     Get,
+    LGet,
+    Lease,
+    Wait,
+    Stale,
     Delete,
     Set,
     Notify,
     ListPartitions,
     Lsd,
+    Scan,
+    ScanResult,
+    Incr,
+    Decr,
+    IncrBy,
+    IncrByFloat,
+    SetNx,
+    GetSet,
+    Cas,
+    GetMeta,
+    Meta,
+    HSet,
+    HGet,
+    HDel,
+    HGetAll,
+    LPush,
+    RPush,
+    LPop,
+    LRange,
+    SAdd,
+    SRem,
+    SMembers,
+    SIsMember,
+    ZAdd,
+    ZRem,
+    ZScore,
+    ZRange,
+    ZRank,
+    Subscribe,
+    Unsubscribe,
+    PSubscribe,
+    PUnsubscribe,
+    Publish,
+    Subscription,
+    Message,
+    PatternMessage,
+    KSubscribe,
+    KUnsubscribe,
+    Event,
+    EventMessage,
+    Multi,
+    Exec,
+    Discard,
+    Watch,
+    Unwatch,
+    ExecResult,
+    Lock,
+    Unlock,
+    Extend,
+    Throttle,
+    PfAdd,
+    PfCount,
+    PfMerge,
+    PfDump,
+    PfLoad,
+    BfAdd,
+    BfExists,
+    FlushAll,
+    Flush,
+    DbSize,
+    Evictions,
+    Coalesced,
+    Stats,
+    Invalidate,
+    Select,
+    Aggregate,
+    Array,
+    Value,
+    NotStored,
     Hit,
     Miss,
     Ack,
     Ok,
     Error,
 }
+
+impl CommandType {
+    /// Whether the command's first argument is a key, which decides the partition it runs on.
+    pub fn has_key(&self) -> bool {
+        matches!(
+            self,
+            CommandType::Get
+                | CommandType::LGet
+                | CommandType::Set
+                | CommandType::Delete
+                | CommandType::Lsd
+                | CommandType::Incr
+                | CommandType::Decr
+                | CommandType::IncrBy
+                | CommandType::IncrByFloat
+                | CommandType::SetNx
+                | CommandType::GetSet
+                | CommandType::Cas
+                | CommandType::GetMeta
+                | CommandType::HSet
+                | CommandType::HGet
+                | CommandType::HDel
+                | CommandType::HGetAll
+                | CommandType::LPush
+                | CommandType::RPush
+                | CommandType::LPop
+                | CommandType::LRange
+                | CommandType::SAdd
+                | CommandType::SRem
+                | CommandType::SMembers
+                | CommandType::SIsMember
+                | CommandType::ZAdd
+                | CommandType::ZRem
+                | CommandType::ZScore
+                | CommandType::ZRange
+                | CommandType::ZRank
+                | CommandType::Lock
+                | CommandType::Unlock
+                | CommandType::Extend
+                | CommandType::Throttle
+                | CommandType::PfAdd
+                | CommandType::PfCount
+                | CommandType::PfMerge
+                | CommandType::PfDump
+                | CommandType::PfLoad
+                | CommandType::BfAdd
+                | CommandType::BfExists
+        )
+    }
+
+    /// Keyless commands the master sends to every partition, summing up their answers.
+    pub fn is_broadcast(&self) -> bool {
+        matches!(
+            self,
+            CommandType::FlushAll
+                | CommandType::Flush
+                | CommandType::DbSize
+                | CommandType::Evictions
+                | CommandType::Coalesced
+                | CommandType::Invalidate
+        )
+    }
+
+    /// Keyed commands whose arguments are all further keys.
+    pub fn is_multi_key(&self) -> bool {
+        matches!(self, CommandType::PfCount | CommandType::PfMerge)
+    }
+
+    /// Keyed commands that bounded loads may hand to a partition other than the key's owner.
+    /// Only plain `GET` qualifies: the master asks the owner again when it misses there, which
+    /// it can't tell apart from a collection, sketch or filter read coming back empty. `LGET`
+    /// is left out too, since the fill lease it hands out lives on the owner, where the
+    /// `SET … LEASE` redeeming it must go.
+    pub fn may_spill(&self) -> bool {
+        matches!(self, CommandType::Get)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ParsedRequest {
     pub cmd: CommandType,
     pub key: Option<String>,
     pub value: Option<String>,
     pub error: Option<Error>,
+    pub args: Vec<String>,
+    /// Namespace picked with an `@<namespace>` prefix, or `None` without one.
+    pub namespace: Option<String>,
+    /// The request without its namespace prefix.
     pub original_rq: String,
 }
+
+impl ParsedRequest {
+    /// Every key the request touches: its routing key and, for multi-key commands, the keys
+    /// that follow it.
+    pub fn keys(&self) -> Vec<&str> {
+        let rest = if self.cmd.is_multi_key() {
+            self.args.as_slice()
+        } else {
+            &[]
+        };
+        self.key.iter().chain(rest).map(String::as_str).collect()
+    }
+}
 pub fn parse_request(mut message: Vec<u8>) -> Result<ParsedRequest, Error> {
     if let Some(pos) = message.iter().position(|&c| c == b'\0') {
         message.truncate(pos);
@@ -33,6 +205,7 @@ pub fn parse_request(mut message: Vec<u8>) -> Result<ParsedRequest, Error> {
 
     let buf =
         std::str::from_utf8(&message).map_err(|_| Error::from_code(ErrorCode::InvalidSequence))?;
+    let (namespace, buf) = split_namespace(buf)?;
     let mut parts: Vec<&str> = buf.splitn(3, char::is_whitespace).collect();
     if parts.last() == Some(&"") || parts.last() == Some(&"\n") || parts.last() == Some(&"\0") {
         parts.pop();
@@ -40,15 +213,25 @@ pub fn parse_request(mut message: Vec<u8>) -> Result<ParsedRequest, Error> {
 
     let cmd = extract_cmd(&parts)?;
 
-    let key = match cmd {
-        CommandType::Get | CommandType::Set | CommandType::Delete | CommandType::Lsd => {
-            extract_key(&parts)
-        }
-        _ => Ok(None),
+    let key = if cmd.has_key() {
+        extract_key(&parts)
+    } else {
+        Ok(None)
     }?;
 
     let value = match cmd {
-        CommandType::Set => extract_value(&parts),
+        // PUBLISH channel message
+        CommandType::Set | CommandType::SetNx | CommandType::GetSet | CommandType::Publish => {
+            extract_value(&parts)
+        }
+        // CAS key version value
+        CommandType::Cas => extract_value(&parts)?
+            .and_then(|rest| {
+                rest.split_once(char::is_whitespace)
+                    .map(|(_, value)| value.to_string())
+            })
+            .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))
+            .map(Some),
         _ => Ok(None),
     }?;
 
@@ -57,16 +240,65 @@ pub fn parse_request(mut message: Vec<u8>) -> Result<ParsedRequest, Error> {
         _ => Ok(None),
     }?;
 
+    let args = match cmd {
+        // GET treats everything after the command as the key
+        CommandType::Get => Vec::new(),
+        _ => extract_args(&parts, key.is_some()),
+    };
+
     Ok(ParsedRequest {
         cmd,
         key,
         value,
         error,
+        args,
+        namespace,
         original_rq: buf.to_string(),
     })
 }
 
-fn extract_error(parts: &Vec<&str>) -> Result<Option<Error>, Error> {
+/// Splits off an `@<namespace>` prefix such as in `@billing GET key`.
+fn split_namespace(buf: &str) -> Result<(Option<String>, &str), Error> {
+    let Some(prefixed) = buf.strip_prefix('@') else {
+        return Ok((None, buf));
+    };
+    let (namespace, rest) = prefixed
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))?;
+    match namespace {
+        "" => Err(Error::from_code(ErrorCode::InvalidRequestArg)),
+        namespace => Ok((Some(namespace.to_string()), rest)),
+    }
+}
+
+/// Prefixes a request for a partition with the namespace it runs in.
+pub fn build_namespaced_request(namespace: Option<&str>, request: &[u8]) -> Vec<u8> {
+    match namespace {
+        Some(namespace) => [format!("@{} ", namespace).as_bytes(), request].concat(),
+        None => request.to_vec(),
+    }
+}
+
+/// Tags a frame on the link between the master and a partition as `#<id> <frame>`. The
+/// partition answers with the request's id, so requests can be in flight side by side and be
+/// answered in any order.
+pub fn build_tagged_frame(id: u64, frame: &[u8]) -> Vec<u8> {
+    let mut tagged = [format!("#{} ", id).as_bytes(), frame].concat();
+    if tagged.last() != Some(&b'\0') {
+        tagged.push(b'\0');
+    }
+    tagged
+}
+
+/// Splits the id off a frame built by `build_tagged_frame`.
+pub fn split_tag(frame: &[u8]) -> Option<(u64, &[u8])> {
+    let rest = frame.strip_prefix(b"#")?;
+    let space = rest.iter().position(|&c| c == b' ')?;
+    let id = str::from_utf8(&rest[..space]).ok()?.parse().ok()?;
+    Some((id, &rest[space + 1..]))
+}
+
+fn extract_error(parts: &[&str]) -> Result<Option<Error>, Error> {
     if parts.len() < 3 {
         Err(Error::from_code(ErrorCode::NotEnoughArgs))
     } else {
@@ -74,17 +306,90 @@ fn extract_error(parts: &Vec<&str>) -> Result<Option<Error>, Error> {
     }
 }
 
-fn extract_cmd(parts: &Vec<&str>) -> Result<CommandType, Error> {
+fn extract_cmd(parts: &[&str]) -> Result<CommandType, Error> {
     if parts.is_empty() {
         Err(Error::from_code(ErrorCode::NotEnoughArgs))
     } else {
         match parts[0] {
             "GET" => Ok(CommandType::Get),
+            "LGET" => Ok(CommandType::LGet),
+            "LSE" => Ok(CommandType::Lease),
+            "WAI" => Ok(CommandType::Wait),
+            "STL" => Ok(CommandType::Stale),
             "DEL" => Ok(CommandType::Delete),
             "SET" => Ok(CommandType::Set),
             "NTF" => Ok(CommandType::Notify),
             "LSP" => Ok(CommandType::ListPartitions),
             "LSD" => Ok(CommandType::Lsd),
+            "SCAN" => Ok(CommandType::Scan),
+            "SCN" => Ok(CommandType::ScanResult),
+            "INCR" => Ok(CommandType::Incr),
+            "DECR" => Ok(CommandType::Decr),
+            "INCRBY" => Ok(CommandType::IncrBy),
+            "INCRBYFLOAT" => Ok(CommandType::IncrByFloat),
+            "SETNX" => Ok(CommandType::SetNx),
+            "GETSET" => Ok(CommandType::GetSet),
+            "CAS" => Ok(CommandType::Cas),
+            "GETMETA" | "OBJECT" => Ok(CommandType::GetMeta),
+            "MET" => Ok(CommandType::Meta),
+            "HSET" => Ok(CommandType::HSet),
+            "HGET" => Ok(CommandType::HGet),
+            "HDEL" => Ok(CommandType::HDel),
+            "HGETALL" => Ok(CommandType::HGetAll),
+            "LPUSH" => Ok(CommandType::LPush),
+            "RPUSH" => Ok(CommandType::RPush),
+            "LPOP" => Ok(CommandType::LPop),
+            "LRANGE" => Ok(CommandType::LRange),
+            "SADD" => Ok(CommandType::SAdd),
+            "SREM" => Ok(CommandType::SRem),
+            "SMEMBERS" => Ok(CommandType::SMembers),
+            "SISMEMBER" => Ok(CommandType::SIsMember),
+            "ZADD" => Ok(CommandType::ZAdd),
+            "ZREM" => Ok(CommandType::ZRem),
+            "ZSCORE" => Ok(CommandType::ZScore),
+            "ZRANGE" => Ok(CommandType::ZRange),
+            "ZRANK" => Ok(CommandType::ZRank),
+            "SUBSCRIBE" => Ok(CommandType::Subscribe),
+            "UNSUBSCRIBE" => Ok(CommandType::Unsubscribe),
+            "PSUBSCRIBE" => Ok(CommandType::PSubscribe),
+            "PUNSUBSCRIBE" => Ok(CommandType::PUnsubscribe),
+            "PUBLISH" => Ok(CommandType::Publish),
+            "SUB" => Ok(CommandType::Subscription),
+            "MSG" => Ok(CommandType::Message),
+            "PMS" => Ok(CommandType::PatternMessage),
+            "KSUBSCRIBE" => Ok(CommandType::KSubscribe),
+            "KUNSUBSCRIBE" => Ok(CommandType::KUnsubscribe),
+            "EVT" => Ok(CommandType::Event),
+            "KEV" => Ok(CommandType::EventMessage),
+            "MULTI" => Ok(CommandType::Multi),
+            "EXEC" => Ok(CommandType::Exec),
+            "DISCARD" => Ok(CommandType::Discard),
+            "WATCH" => Ok(CommandType::Watch),
+            "UNWATCH" => Ok(CommandType::Unwatch),
+            "EXC" => Ok(CommandType::ExecResult),
+            "LOCK" => Ok(CommandType::Lock),
+            "UNLOCK" => Ok(CommandType::Unlock),
+            "EXTEND" => Ok(CommandType::Extend),
+            "THROTTLE" => Ok(CommandType::Throttle),
+            "PFADD" => Ok(CommandType::PfAdd),
+            "PFCOUNT" => Ok(CommandType::PfCount),
+            "PFMERGE" => Ok(CommandType::PfMerge),
+            "PFDUMP" => Ok(CommandType::PfDump),
+            "PFLOAD" => Ok(CommandType::PfLoad),
+            "BF.ADD" => Ok(CommandType::BfAdd),
+            "BF.EXISTS" => Ok(CommandType::BfExists),
+            "FLUSHALL" => Ok(CommandType::FlushAll),
+            "FLUSH" => Ok(CommandType::Flush),
+            "DBSIZE" => Ok(CommandType::DbSize),
+            "EVICTIONS" => Ok(CommandType::Evictions),
+            "COALESCED" => Ok(CommandType::Coalesced),
+            "STATS" => Ok(CommandType::Stats),
+            "INVALIDATE" => Ok(CommandType::Invalidate),
+            "SELECT" => Ok(CommandType::Select),
+            "AGG" => Ok(CommandType::Aggregate),
+            "ARR" => Ok(CommandType::Array),
+            "VAL" => Ok(CommandType::Value),
+            "NST" => Ok(CommandType::NotStored),
             "HIT" => Ok(CommandType::Hit),
             "MSS" => Ok(CommandType::Miss),
             "ACK" => Ok(CommandType::Ack),
@@ -95,7 +400,7 @@ fn extract_cmd(parts: &Vec<&str>) -> Result<CommandType, Error> {
     }
 }
 
-fn extract_key(parts: &Vec<&str>) -> Result<Option<String>, Error> {
+fn extract_key(parts: &[&str]) -> Result<Option<String>, Error> {
     if parts.len() < 2 {
         Err(Error::from_code(ErrorCode::NotEnoughArgs))
     } else if parts[0].to_uppercase() == "GET" && parts.len() >= 3 {
@@ -106,7 +411,20 @@ fn extract_key(parts: &Vec<&str>) -> Result<Option<String>, Error> {
     }
 }
 
-fn extract_value(parts: &Vec<&str>) -> Result<Option<String>, Error> {
+/// Whitespace-separated tokens following the command, or following the key for keyed commands.
+fn extract_args(parts: &[&str], has_key: bool) -> Vec<String> {
+    let rest = if has_key {
+        parts.get(2..).unwrap_or_default()
+    } else {
+        parts.get(1..).unwrap_or_default()
+    };
+    rest.iter()
+        .flat_map(|part| part.split_whitespace())
+        .map(str::to_string)
+        .collect()
+}
+
+fn extract_value(parts: &[&str]) -> Result<Option<String>, Error> {
     if parts.len() < 3 {
         Err(Error::from_code(ErrorCode::NotEnoughArgs))
     } else {
@@ -114,15 +432,381 @@ fn extract_value(parts: &Vec<&str>) -> Result<Option<String>, Error> {
     }
 }
 
-pub fn build_hit_response(key: &str, value: &str) -> Vec<u8> {
-    format!("HIT {} {}\0", key, value).into_bytes()
+/// Options that may trail a `SET` value.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    pub condition: Option<SetCondition>,
+    /// Tags from `TAGS tag1,tag2,...`, for `INVALIDATE`.
+    pub tags: Vec<String>,
+    /// Token from `LEASE <token>`, filling a key leased by `LGET`. Excludes `NX` and `XX`.
+    pub lease: Option<u64>,
+    /// Hard expiry from `EX <seconds>`.
+    pub ttl: Option<Duration>,
+    /// Soft expiry from `SOFT <seconds>`, after which reads flag the value as stale.
+    pub soft_ttl: Option<Duration>,
+}
+
+fn parse_set_option_tokens(tokens: &[&str]) -> Option<SetOptions> {
+    let mut options = SetOptions::default();
+    let mut tokens = tokens.iter();
+    while let Some(token) = tokens.next() {
+        let condition = match *token {
+            "NX" => SetCondition::IfAbsent,
+            "XX" => SetCondition::IfPresent,
+            "TAGS" if options.tags.is_empty() => {
+                let tags = tokens.next()?;
+                if tags.split(',').any(str::is_empty) {
+                    return None;
+                }
+                options.tags = tags.split(',').map(str::to_string).collect();
+                continue;
+            }
+            "LEASE" if options.lease.is_none() => {
+                options.lease = Some(tokens.next()?.parse().ok()?);
+                continue;
+            }
+            "EX" if options.ttl.is_none() => {
+                options.ttl = Some(Duration::from_secs(tokens.next()?.parse().ok()?));
+                continue;
+            }
+            "SOFT" if options.soft_ttl.is_none() => {
+                options.soft_ttl = Some(Duration::from_secs(tokens.next()?.parse().ok()?));
+                continue;
+            }
+            _ => return None,
+        };
+        if options.condition.replace(condition).is_some() {
+            return None;
+        }
+    }
+    if options.lease.is_some() && options.condition.is_some() {
+        return None;
+    }
+    // a soft expiry at or after the hard one would never be seen
+    if let (Some(soft_ttl), Some(ttl)) = (options.soft_ttl, options.ttl) {
+        if soft_ttl >= ttl {
+            return None;
+        }
+    }
+    Some(options)
+}
+
+/// Number of arguments a `SET` option keyword takes, or `None` for other words.
+fn set_option_arity(token: &str) -> Option<usize> {
+    match token {
+        "NX" | "XX" => Some(0),
+        "TAGS" | "LEASE" | "EX" | "SOFT" => Some(1),
+        _ => None,
+    }
+}
+
+/// Whether `tokens` are all option keywords, each followed by its arguments.
+fn is_set_option_sequence(tokens: &[&str]) -> bool {
+    let mut rest = tokens;
+    while let Some((token, after)) = rest.split_first() {
+        match set_option_arity(token) {
+            Some(arity) if arity <= after.len() => rest = &after[arity..],
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Splits trailing options such as `NX`, `XX`, `TAGS a,b` or `EX 60` off a `SET` value.
+/// Options begin at the first token from which the rest reads as option keywords with
+/// their arguments, so a value may still contain those words as long as something else
+/// follows them. Options that are malformed or can't be combined, such as `SOFT` at or
+/// after `EX` or `LEASE` with `NX`, are rejected rather than kept in the value, and so is
+/// a keyword missing its argument at the end.
+pub fn split_set_options(value: &str) -> Result<(&str, SetOptions), Error> {
+    let mut tokens: Vec<(usize, &str)> = Vec::new();
+    let mut start = None;
+    for (idx, c) in value
+        .char_indices()
+        .chain(std::iter::once((value.len(), ' ')))
+    {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(idx),
+            (true, Some(from)) => {
+                tokens.push((from, &value[from..idx]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    let invalid = || Error::from_code(ErrorCode::InvalidRequestArg);
+    let words: Vec<&str> = tokens.iter().map(|(_, token)| *token).collect();
+    match (1..words.len()).find(|&i| is_set_option_sequence(&words[i..])) {
+        Some(i) => {
+            let options = parse_set_option_tokens(&words[i..]).ok_or_else(invalid)?;
+            Ok((value[..tokens[i].0].trim_end(), options))
+        }
+        None if words.len() > 1 && set_option_arity(words[words.len() - 1]) == Some(1) => {
+            Err(invalid())
+        }
+        None => Ok((value, SetOptions::default())),
+    }
+}
+
+/// Parses a `ZRANGE ... BYSCORE` bound: a number, `(number` for an exclusive bound,
+/// or `-inf`/`+inf`.
+pub fn parse_score_bound(arg: &str) -> Result<Bound<f64>, Error> {
+    let invalid = || Error::from_code(ErrorCode::InvalidRequestArg);
+    match arg {
+        "-inf" | "+inf" | "inf" => Ok(Bound::Unbounded),
+        _ => match arg.strip_prefix('(') {
+            Some(score) => score.parse().map(Bound::Excluded).map_err(|_| invalid()),
+            None => arg.parse().map(Bound::Included).map_err(|_| invalid()),
+        },
+    }
+}
+
+/// A transaction as the master hands it to a partition: the versions the watched keys must
+/// still be at, and the queued requests to run if they are.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecBatch {
+    pub watches: Vec<(String, u64)>,
+    pub requests: Vec<String>,
+}
+
+/// Splits `<header>\n<bodies>`, where the trailing header tokens give each body's length.
+fn split_length_prefixed(header: &[&str], bodies: &str) -> Option<Vec<String>> {
+    let mut rest = bodies;
+    let mut items = Vec::with_capacity(header.len());
+    for len in header {
+        let len: usize = len.parse().ok()?;
+        if len > rest.len() || !rest.is_char_boundary(len) {
+            return None;
+        }
+        let (item, tail) = rest.split_at(len);
+        items.push(item.to_string());
+        rest = tail;
+    }
+    rest.is_empty().then_some(items)
+}
+
+fn join_length_prefixed(mut header: String, items: &[&str]) -> String {
+    for item in items {
+        header.push_str(&format!(" {}", item.len()));
+    }
+    header.push('\n');
+    items.iter().for_each(|item| header.push_str(item));
+    header
+}
+
+/// `EXEC <watches> [<key> <version>]... [<length>]...` followed by a newline and the queued
+/// requests back to back, each as long as its length says. Lengths rather than separators
+/// keep requests intact whatever their values contain.
+pub fn build_exec_request(batch: &ExecBatch) -> Vec<u8> {
+    let mut header = format!("EXEC {}", batch.watches.len());
+    for (key, version) in &batch.watches {
+        header.push_str(&format!(" {} {}", key, version));
+    }
+    let requests: Vec<&str> = batch.requests.iter().map(String::as_str).collect();
+    let mut s = join_length_prefixed(header, &requests);
+    s.push('\0');
+    s.into_bytes()
+}
+
+pub fn parse_exec_request(request: &str) -> Result<ExecBatch, Error> {
+    let invalid = || Error::from_code(ErrorCode::InvalidRequestArg);
+    let (header, bodies) = request.split_once('\n').ok_or_else(invalid)?;
+    let tokens: Vec<&str> = header.split_whitespace().skip(1).collect();
+    let (count, rest) = tokens.split_first().ok_or_else(invalid)?;
+    let count: usize = count.parse().map_err(|_| invalid())?;
+    if rest.len() < count * 2 {
+        return Err(invalid());
+    }
+    let (watched, lengths) = rest.split_at(count * 2);
+    let watches = watched
+        .chunks(2)
+        .map(|pair| Ok((pair[0].to_string(), pair[1].parse().map_err(|_| invalid())?)))
+        .collect::<Result<_, Error>>()?;
+    let requests = split_length_prefixed(lengths, bodies).ok_or_else(invalid)?;
+    Ok(ExecBatch { watches, requests })
+}
+
+/// `EXC [<length>]...` followed by a newline and one response per queued request, without
+/// their terminators, laid out like the requests of `build_exec_request`.
+pub fn build_exec_response(responses: &[Vec<u8>]) -> Vec<u8> {
+    let responses: Vec<String> = responses
+        .iter()
+        .map(|r| {
+            String::from_utf8_lossy(r)
+                .trim_end_matches('\0')
+                .to_string()
+        })
+        .collect();
+    let responses: Vec<&str> = responses.iter().map(String::as_str).collect();
+    let mut s = join_length_prefixed("EXC".to_string(), &responses);
+    s.push('\0');
+    s.into_bytes()
+}
+
+pub fn parse_exec_response(response: &[u8]) -> Option<Vec<String>> {
+    let response = str::from_utf8(response).ok()?.trim_end_matches('\0');
+    let (header, bodies) = response.split_once('\n')?;
+    let mut tokens = header.split_whitespace();
+    (tokens.next()? == "EXC").then_some(())?;
+    split_length_prefixed(&tokens.collect::<Vec<_>>(), bodies)
+}
+
+/// Asks a partition for the current versions of `keys`, answered with an `ARR` of versions
+/// in which 0 stands for a missing key.
+pub fn build_watch_request(keys: &[String]) -> Vec<u8> {
+    format!("WATCH {}\0", keys.join(" ")).into_bytes()
+}
+
+/// `AGG <total> <failed partitions>` followed by one line per partition with its address and
+/// its own answer, so a caller can tell which partitions a broadcast did not reach.
+pub fn build_aggregate_response(total: u64, replies: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let failed = replies
+        .iter()
+        .filter(|(_, reply)| reply.starts_with(b"ERR"))
+        .count();
+    let mut s = format!("AGG {} {}", total, failed);
+    for (addr, reply) in replies {
+        s.push_str(&format!(
+            "\n{} {}",
+            addr,
+            String::from_utf8_lossy(reply).trim_end_matches('\0')
+        ));
+    }
+    s.push('\0');
+    s.into_bytes()
+}
+
+/// Asks a partition for the registers of the HyperLogLog at `key`, answered with `VAL <hex>`.
+pub fn build_pfdump_request(key: &str) -> Vec<u8> {
+    format!("PFDUMP {}\0", key).into_bytes()
+}
+
+/// Merges hex-encoded HyperLogLog registers into the HyperLogLog at `key`.
+pub fn build_pfload_request(key: &str, registers: &str) -> Vec<u8> {
+    format!("PFLOAD {} {}\0", key, registers).into_bytes()
+}
+
+/// `OK QUEUED`, acknowledging a request added to a `MULTI` block.
+pub fn build_queued_response() -> Vec<u8> {
+    "OK QUEUED\0".to_string().into_bytes()
+}
+
+/// Page size used by `SCAN` when no `COUNT` is given.
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Arguments of `SCAN cursor [MATCH pattern] [COUNT n]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanArgs {
+    pub cursor: String,
+    pub pattern: Option<String>,
+    pub count: usize,
+}
+
+pub fn parse_scan_args(args: &[String]) -> Result<ScanArgs, Error> {
+    let cursor = args
+        .first()
+        .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))?
+        .to_string();
+    let mut scan = ScanArgs {
+        cursor,
+        pattern: None,
+        count: DEFAULT_SCAN_COUNT,
+    };
+    let mut rest = args[1..].iter();
+    while let Some(option) = rest.next() {
+        let value = rest
+            .next()
+            .ok_or_else(|| Error::from_code(ErrorCode::NotEnoughArgs))?;
+        match option.to_uppercase().as_str() {
+            "MATCH" => scan.pattern = Some(value.to_string()),
+            "COUNT" => {
+                scan.count = value
+                    .parse()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .ok_or_else(|| Error::from_code(ErrorCode::InvalidRequestArg))?
+            }
+            _ => return Err(Error::from_code(ErrorCode::InvalidRequestArg)),
+        }
+    }
+    Ok(scan)
+}
+
+/// Hex-encodes a key so it can travel inside a whitespace-separated cursor.
+pub fn encode_cursor_key(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn decode_cursor_key(cursor: &str) -> Result<String, Error> {
+    let invalid = || Error::from_code(ErrorCode::InvalidRequestArg);
+    if !cursor.len().is_multiple_of(2) || !cursor.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&cursor[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())?;
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+pub fn build_scan_request(cursor: &str, pattern: Option<&str>, count: usize) -> Vec<u8> {
+    match pattern {
+        Some(pattern) => format!("SCAN {} MATCH {} COUNT {}\0", cursor, pattern, count),
+        None => format!("SCAN {} COUNT {}\0", cursor, count),
+    }
+    .into_bytes()
+}
+
+/// `SCN <cursor>` followed by one key per line. A cursor of `0` means the scan is complete.
+pub fn build_scan_response(cursor: &str, keys: &[String]) -> Vec<u8> {
+    let mut s = format!("SCN {}", cursor);
+    for key in keys {
+        s.push('\n');
+        s.push_str(key);
+    }
+    s.push('\0');
+    s.into_bytes()
+}
+
+pub fn parse_scan_response(response: &[u8]) -> Option<(String, Vec<String>)> {
+    let response = str::from_utf8(response).ok()?.trim_end_matches('\0');
+    let mut lines = response.split('\n');
+    let cursor = lines.next()?.strip_prefix("SCN ")?.to_string();
+    Some((cursor, lines.map(str::to_string).collect()))
+}
+
+/// `HIT <key> <version> <value>`. The version can be passed to `CAS` for optimistic concurrency.
+pub fn build_hit_response(key: &str, version: u64, value: &str) -> Vec<u8> {
+    format!("HIT {} {} {}\0", key, version, value).into_bytes()
+}
+
+/// `STL <key> <version> <lease> <value>`, a hit on a value past its soft expiry. The first
+/// client to see it stale gets a lease token to refresh it with `SET ... LEASE <token>`, and
+/// the others get a lease of `0` while they keep using the stale value.
+pub fn build_stale_response(key: &str, version: u64, lease: u64, value: &str) -> Vec<u8> {
+    format!("STL {} {} {} {}\0", key, version, lease, value).into_bytes()
+}
+
+/// `LSE <key> <token>`, handing the first client to miss `key` the lease to fill it.
+pub fn build_lease_response(key: &str, token: u64) -> Vec<u8> {
+    format!("LSE {} {}\0", key, token).into_bytes()
+}
+
+/// `WAI <key>`, telling a client that missed `key` that another client is filling it, so it
+/// should retry shortly or make do with a stale value.
+pub fn build_wait_response(key: &str) -> Vec<u8> {
+    format!("WAI {}\0", key).into_bytes()
 }
 
-pub fn build_lsd_response(cache: &LruCache<String, String>) -> Vec<u8> {
+pub fn build_lsd_response<'a, V: fmt::Display + 'a>(
+    entries: impl Iterator<Item = (&'a String, &'a V)>,
+) -> Vec<u8> {
     let mut s: String = "".to_owned();
-    for (key, val) in cache.iter() {
+    for (key, val) in entries {
         s.push_str(&format!("Key: {}, Value: {} \n", key, val).to_owned());
     }
+    s.push('\0');
     s.into_bytes()
 }
 
@@ -130,22 +814,321 @@ pub fn build_lsp_response(partitions_str: String) -> Vec<u8> {
     format!("LSP {}\0", partitions_str).into_bytes()
 }
 
+/// `MET <key> version <n> created <time> last_access <time> access_count <n> size <bytes>`,
+/// with times in RFC 3339.
+pub fn build_meta_response(key: &str, entry: &Entry) -> Vec<u8> {
+    let format_time = |time| DateTime::<Utc>::from(time).to_rfc3339();
+    format!(
+        "MET {} version {} created {} last_access {} access_count {} size {}\0",
+        key,
+        entry.version,
+        format_time(entry.created_at),
+        format_time(entry.last_access),
+        entry.access_count,
+        entry.size()
+    )
+    .into_bytes()
+}
+
 pub fn build_miss_response(key: &str) -> Vec<u8> {
     format!("MSS {}\0", key).into_bytes()
 }
 
+/// `STS <name> <count>...` with a partition's counters, or, from the master, with each counter
+/// summed over the partitions, followed by one line per partition with its address and its
+/// own answer, like `AGG`.
+pub fn build_stats_response(stats: &[(String, u64)], replies: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut s = String::from("STS");
+    for (name, count) in stats {
+        s.push_str(&format!(" {} {}", name, count));
+    }
+    for (addr, reply) in replies {
+        s.push_str(&format!(
+            "\n{} {}",
+            addr,
+            String::from_utf8_lossy(reply).trim_end_matches('\0')
+        ));
+    }
+    s.push('\0');
+    s.into_bytes()
+}
+
+/// The counters of a partition's `STS` answer.
+pub fn parse_stats_response(response: &[u8]) -> Option<Vec<(String, u64)>> {
+    let response = str::from_utf8(response).ok()?.trim_end_matches('\0');
+    let tokens: Vec<&str> = response.strip_prefix("STS")?.split_whitespace().collect();
+    if !tokens.len().is_multiple_of(2) {
+        return None;
+    }
+    tokens
+        .chunks(2)
+        .map(|pair| Some((pair[0].to_string(), pair[1].parse().ok()?)))
+        .collect()
+}
+
+/// `ARR <n>` followed by one item per line.
+pub fn build_array_response(items: &[String]) -> Vec<u8> {
+    let mut s = format!("ARR {}", items.len());
+    for item in items {
+        s.push('\n');
+        s.push_str(item);
+    }
+    s.push('\0');
+    s.into_bytes()
+}
+
+pub fn parse_array_response(response: &[u8]) -> Option<Vec<String>> {
+    let response = str::from_utf8(response).ok()?.trim_end_matches('\0');
+    let mut lines = response.split('\n');
+    let len: usize = lines.next()?.strip_prefix("ARR ")?.parse().ok()?;
+    let items: Vec<String> = lines.map(str::to_string).collect();
+    (items.len() == len).then_some(items)
+}
+
+pub fn parse_value_response(response: &[u8]) -> Option<&str> {
+    str::from_utf8(response)
+        .ok()?
+        .trim_end_matches('\0')
+        .strip_prefix("VAL ")
+}
+
+pub fn build_value_response(value: &str) -> Vec<u8> {
+    format!("VAL {}\0", value).into_bytes()
+}
+
+/// A conditional write whose condition did not hold.
+pub fn build_not_stored_response() -> Vec<u8> {
+    "NST\0".to_string().into_bytes()
+}
+
 pub fn build_ok_response() -> Vec<u8> {
     "OK\0".to_string().into_bytes()
 }
 
+pub fn build_best_effort_ok_response() -> Vec<u8> {
+    "OK BEST_EFFORT\0".to_string().into_bytes()
+}
+
+/// `BMS <key>`: a miss reported because the key's partition could not be reached. It has a
+/// status word of its own since keys may contain spaces, so nothing after the key can mark it.
+pub fn build_best_effort_miss_response(key: &str) -> Vec<u8> {
+    format!("BMS {}\0", key).into_bytes()
+}
+
 pub fn build_error_response(err: &Error) -> Vec<u8> {
     format!("ERR {} {}\0", err.code.to_u8(), err.msg).into_bytes()
 }
 
-pub fn build_notify_request() -> Vec<u8> {
-    "NTF\0".to_string().into_bytes()
+/// `SUB <kind> <channel> <count>`, confirming a (un)subscription. `count` is the number of
+/// channels and patterns the connection is still subscribed to.
+pub fn build_subscription_response(kind: &str, channel: &str, count: usize) -> Vec<u8> {
+    format!("SUB {} {} {}\0", kind, channel, count).into_bytes()
+}
+
+/// `MSG <channel> <message>`, pushed to subscribers of `channel`.
+pub fn build_message_response(channel: &str, message: &str) -> Vec<u8> {
+    format!("MSG {} {}\0", channel, message).into_bytes()
+}
+
+/// `PMS <pattern> <channel> <message>`, pushed to subscribers of a matching pattern.
+pub fn build_pattern_message_response(pattern: &str, channel: &str, message: &str) -> Vec<u8> {
+    format!("PMS {} {} {}\0", pattern, channel, message).into_bytes()
+}
+
+/// Opens a partition's event stream: a bare `EVT`, followed by one `EVT` frame per event
+/// once the master has acknowledged it.
+/// `EVT <token>`, opening a partition's event stream with the token it registered with.
+pub fn build_event_stream_request(token: &str) -> Vec<u8> {
+    format!("EVT {}\0", token).into_bytes()
+}
+
+/// `EVT <event> <key>`, sent by a partition when one of its keys changes.
+pub fn build_event_request(event: KeyEvent, key: &str) -> Vec<u8> {
+    format!("EVT {} {}\0", event, key).into_bytes()
+}
+
+/// `KEV <event> <key>`, pushed to clients watching a matching key pattern.
+pub fn build_event_response(event: KeyEvent, key: &str) -> Vec<u8> {
+    format!("KEV {} {}\0", event, key).into_bytes()
+}
+
+pub fn build_notify_request(weight: u32) -> Vec<u8> {
+    format!("NTF {}\0", weight).into_bytes()
 }
 
 pub fn build_ack_response() -> Vec<u8> {
     "ACK\0".to_string().into_bytes()
 }
+
+/// `ACK <token>`, accepting a partition with the token it opens its event stream with.
+pub fn build_notify_ack_response(token: &str) -> Vec<u8> {
+    format!("ACK {}\0", token).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn parses_scan_options() {
+        let scan = parse_scan_args(&args("0 MATCH user:* COUNT 50")).unwrap();
+        assert_eq!(scan.cursor, "0");
+        assert_eq!(scan.pattern.as_deref(), Some("user:*"));
+        assert_eq!(scan.count, 50);
+        assert_eq!(
+            parse_scan_args(&args("0")).unwrap().count,
+            DEFAULT_SCAN_COUNT
+        );
+        assert!(parse_scan_args(&args("0 COUNT 0")).is_err());
+        assert!(parse_scan_args(&args("0 LIMIT 3")).is_err());
+    }
+
+    #[test]
+    fn splits_trailing_set_options() {
+        let split = |value| split_set_options(value).unwrap();
+        assert_eq!(split("v NX").0, "v");
+        assert_eq!(
+            split("hello world XX").1.condition,
+            Some(SetCondition::IfPresent)
+        );
+        assert_eq!(
+            split("NX is a word NX"),
+            (
+                "NX is a word",
+                SetOptions {
+                    condition: Some(SetCondition::IfAbsent),
+                    ..SetOptions::default()
+                }
+            )
+        );
+        assert_eq!(split("NX").1, SetOptions::default());
+        assert_eq!(
+            split("EX 60 is not an option"),
+            ("EX 60 is not an option", SetOptions::default())
+        );
+        let (value, options) = split("<p>hi</p> TAGS user:1,post:7 NX");
+        assert_eq!(value, "<p>hi</p>");
+        assert_eq!(options.tags, ["user:1", "post:7"]);
+        assert_eq!(split("v LEASE 42").1.lease, Some(42));
+        let options = split("v SOFT 10 EX 60").1;
+        assert_eq!(
+            (options.soft_ttl, options.ttl),
+            (Some(Duration::from_secs(10)), Some(Duration::from_secs(60)))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_set_options() {
+        for value in [
+            "v NX XX",
+            "v TAGS a,,b",
+            "v EX soon",
+            "v EX 10 EX 20",
+            // a soft expiry at or after the hard one would never be seen
+            "v SOFT 60 EX 10",
+            "v LEASE 42 NX",
+            "v TAGS",
+        ] {
+            assert!(split_set_options(value).is_err(), "{} was accepted", value);
+        }
+    }
+
+    #[test]
+    fn lease_fills_stay_on_the_owner() {
+        let spills = |request: &[u8]| parse_request(request.to_vec()).unwrap().cmd.may_spill();
+        assert!(spills(b"GET key"));
+        assert!(!spills(b"LGET key"));
+        assert!(!spills(b"SET key value LEASE 7"));
+    }
+
+    #[test]
+    fn cas_splits_version_from_value() {
+        let request = parse_request(b"CAS key 7 new value".to_vec()).unwrap();
+        assert_eq!(request.args[0], "7");
+        assert_eq!(request.value.as_deref(), Some("new value"));
+    }
+
+    #[test]
+    fn publish_keeps_message_whitespace() {
+        let request = parse_request(b"PUBLISH news hello  world".to_vec()).unwrap();
+        assert_eq!(request.args[0], "news");
+        assert_eq!(request.value.as_deref(), Some("hello  world"));
+    }
+
+    #[test]
+    fn namespace_prefix_is_split_off() {
+        let request = parse_request(b"@billing SET key value\0".to_vec()).unwrap();
+        assert_eq!(request.namespace.as_deref(), Some("billing"));
+        assert_eq!(request.key.as_deref(), Some("key"));
+        assert_eq!(request.original_rq, "SET key value");
+        assert_eq!(
+            build_namespaced_request(Some("billing"), b"SET key value"),
+            b"@billing SET key value"
+        );
+
+        assert!(parse_request(b"@ GET key".to_vec()).is_err());
+    }
+
+    #[test]
+    fn stats_responses_round_trip() {
+        let stats = vec![("memory_hits".to_string(), 3), ("disk_hits".to_string(), 1)];
+        let response = build_stats_response(&stats, &[]);
+        assert_eq!(response, b"STS memory_hits 3 disk_hits 1\0");
+        assert_eq!(parse_stats_response(&response), Some(stats));
+        assert_eq!(parse_stats_response(b"STS memory_hits\0"), None);
+        assert_eq!(parse_stats_response(b"VAL 3\0"), None);
+    }
+
+    #[test]
+    fn tagged_frames_round_trip() {
+        let frame = build_tagged_frame(42, b"@billing GET key");
+        assert_eq!(frame, b"#42 @billing GET key\0");
+        assert_eq!(split_tag(&frame), Some((42, &b"@billing GET key\0"[..])));
+        assert_eq!(split_tag(b"GET key\0"), None);
+        assert_eq!(split_tag(b"#x GET key\0"), None);
+    }
+
+    #[test]
+    fn exec_batches_round_trip() {
+        let batch = ExecBatch {
+            watches: vec![("{u:1}:a".to_string(), 7)],
+            requests: vec![
+                "SET {u:1}:a two\nlines".to_string(),
+                "INCR {u:1}:n".to_string(),
+            ],
+        };
+        let request = String::from_utf8(build_exec_request(&batch)).unwrap();
+        assert_eq!(
+            parse_exec_request(request.trim_end_matches('\0')).unwrap(),
+            batch
+        );
+
+        let responses = vec![b"OK\0".to_vec(), b"ARR 2\na\nb\0".to_vec()];
+        assert_eq!(
+            parse_exec_response(&build_exec_response(&responses)),
+            Some(vec!["OK".to_string(), "ARR 2\na\nb".to_string()])
+        );
+        assert!(parse_exec_request("EXEC 1 k\n").is_err());
+    }
+
+    #[test]
+    fn cursor_keys_round_trip() {
+        let key = "{user:1} cart";
+        assert_eq!(decode_cursor_key(&encode_cursor_key(key)).unwrap(), key);
+        assert!(decode_cursor_key("zz").is_err());
+    }
+
+    #[test]
+    fn scan_response_round_trips() {
+        let keys = vec!["a".to_string(), "b c".to_string()];
+        let response = build_scan_response("0", &keys);
+        assert_eq!(
+            parse_scan_response(&response),
+            Some(("0".to_string(), keys))
+        );
+    }
+}
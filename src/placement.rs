@@ -0,0 +1,610 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hasher;
+use std::str::FromStr;
+use twox_hash::XxHash64;
+
+use crate::error::{Error, ErrorCode};
+
+/// Table size used by `Maglev`. Must be prime and much larger than the number of partitions.
+pub const MAGLEV_TABLE_SIZE: usize = 65537;
+/// Largest weight a node is placed with. Heavier nodes are capped to it, so that a single
+/// registration cannot blow up the ring or the bucket list.
+pub const MAX_WEIGHT: u32 = 1000;
+/// Most virtual nodes per unit of weight on a `ConsistentRing`.
+pub const MAX_VIRTUAL_NODES: u32 = 1000;
+
+pub(crate) fn hash_with_seed(data: &str, seed: u64) -> u64 {
+    let mut hasher = XxHash64::with_seed(seed);
+    hasher.write(data.as_bytes());
+    hasher.finish()
+}
+
+fn hash(data: &str) -> u64 {
+    hash_with_seed(data, 0)
+}
+
+/// Part of `key` that decides its placement. Like Redis hash tags, when the key contains a
+/// non-empty `{...}` section only the text between the first `{` and the next `}` is hashed,
+/// so `{user:1}:profile` and `{user:1}:cart` always land on the same node.
+pub fn hash_tag(key: &str) -> &str {
+    if let Some(open) = key.find('{') {
+        if let Some(len) = key[open + 1..].find('}') {
+            if len > 0 {
+                return &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    key
+}
+
+/// Maps keys onto nodes. Nodes are identified by their `to_string()` representation,
+/// and a node with weight `w` should receive roughly `w` times the keys of a node with weight 1.
+/// Weights above `MAX_WEIGHT` count as `MAX_WEIGHT`.
+pub trait Placement<T>: Send {
+    fn add_node(&mut self, node: &T, weight: u32);
+    fn remove_node(&mut self, node: &T);
+    fn get_node(&self, key: &str) -> Option<&T>;
+
+    /// Distinct nodes in the order they should take over `key`, starting with its owner.
+    /// Strategies without a natural fallback order only return the owner.
+    fn candidates(&self, key: &str) -> Vec<&T> {
+        self.get_node(key).into_iter().collect()
+    }
+}
+
+/// Consistent hashing with bounded loads: take the first candidate whose load, including
+/// the request being placed, stays within `load_factor` times the average load.
+/// Falls back to the owner when every candidate is full.
+pub fn bounded_choice<'a, T>(
+    candidates: &[&'a T],
+    load: impl Fn(&T) -> usize,
+    total_load: usize,
+    num_nodes: usize,
+    load_factor: f64,
+) -> Option<&'a T> {
+    let average = (total_load + 1) as f64 / num_nodes.max(1) as f64;
+    let capacity = (average * load_factor).ceil() as usize;
+    candidates
+        .iter()
+        .find(|node| load(node) < capacity)
+        .or_else(|| candidates.first())
+        .copied()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementKind {
+    Ring,
+    Rendezvous,
+    Jump,
+    Maglev,
+}
+
+impl PlacementKind {
+    pub const ALL: [PlacementKind; 4] = [
+        PlacementKind::Ring,
+        PlacementKind::Rendezvous,
+        PlacementKind::Jump,
+        PlacementKind::Maglev,
+    ];
+
+    pub fn build<T>(&self, virtual_nodes: u32) -> Box<dyn Placement<T>>
+    where
+        T: ToString + Clone + Send + 'static,
+    {
+        match self {
+            PlacementKind::Ring => Box::new(ConsistentRing::new(virtual_nodes)),
+            PlacementKind::Rendezvous => Box::new(Rendezvous::new()),
+            PlacementKind::Jump => Box::new(JumpHash::new()),
+            PlacementKind::Maglev => Box::new(Maglev::new(MAGLEV_TABLE_SIZE)),
+        }
+    }
+}
+
+impl fmt::Display for PlacementKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            PlacementKind::Ring => "ring",
+            PlacementKind::Rendezvous => "rendezvous",
+            PlacementKind::Jump => "jump",
+            PlacementKind::Maglev => "maglev",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for PlacementKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ring" => Ok(PlacementKind::Ring),
+            "rendezvous" => Ok(PlacementKind::Rendezvous),
+            "jump" => Ok(PlacementKind::Jump),
+            "maglev" => Ok(PlacementKind::Maglev),
+            _ => Err(Error::from_code(ErrorCode::InvalidRequestArg)),
+        }
+    }
+}
+
+/// Classic consistent hashing: every node owns `virtual_nodes * weight` points on a hash ring
+/// and a key belongs to the first point at or after its own hash.
+pub struct ConsistentRing<T> {
+    virtual_nodes: u32,
+    points: Vec<(u64, T)>,
+}
+
+impl<T: ToString + Clone> ConsistentRing<T> {
+    pub fn new(virtual_nodes: u32) -> Self {
+        Self {
+            virtual_nodes: virtual_nodes.min(MAX_VIRTUAL_NODES),
+            points: Vec::new(),
+        }
+    }
+}
+
+impl<T: ToString + Clone + Send> Placement<T> for ConsistentRing<T> {
+    fn add_node(&mut self, node: &T, weight: u32) {
+        self.remove_node(node);
+        let id = node.to_string();
+        // both factors are capped, so this only fails if the caps are raised too far
+        let points = self
+            .virtual_nodes
+            .checked_mul(weight.min(MAX_WEIGHT))
+            .expect("virtual node count overflows u32");
+        for i in 0..points {
+            self.points
+                .push((hash(&format!("{}:{}", id, i)), node.clone()));
+        }
+        self.points.sort_by_key(|(point, _)| *point);
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        let id = node.to_string();
+        self.points.retain(|(_, n)| n.to_string() != id);
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let h = hash(key);
+        let idx = self.points.partition_point(|(point, _)| *point < h) % self.points.len();
+        Some(&self.points[idx].1)
+    }
+
+    fn candidates(&self, key: &str) -> Vec<&T> {
+        let start = match self.get_node(key) {
+            Some(_) => self.points.partition_point(|(point, _)| *point < hash(key)),
+            None => return Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        (0..self.points.len())
+            .map(|i| &self.points[(start + i) % self.points.len()].1)
+            .filter(|node| seen.insert(node.to_string()))
+            .collect()
+    }
+}
+
+/// Highest random weight hashing: every node scores the key and the best score wins.
+/// Weights use the logarithmic method, so removing a node only moves the keys it owned.
+pub struct Rendezvous<T> {
+    nodes: Vec<(T, u32)>,
+}
+
+impl<T: ToString + Clone> Rendezvous<T> {
+    pub fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+}
+
+impl<T: ToString + Clone> Rendezvous<T> {
+    fn scores(&self, key: &str) -> Vec<(&T, f64)> {
+        self.nodes
+            .iter()
+            .map(|(node, weight)| {
+                let h = hash(&format!("{}:{}", node.to_string(), key));
+                // map the hash into (0, 1) so the logarithm stays finite
+                let unit = (h as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+                (node, *weight as f64 / -unit.ln())
+            })
+            .collect()
+    }
+}
+
+impl<T: ToString + Clone> Default for Rendezvous<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ToString + Clone + Send> Placement<T> for Rendezvous<T> {
+    fn add_node(&mut self, node: &T, weight: u32) {
+        self.remove_node(node);
+        self.nodes.push((node.clone(), weight.min(MAX_WEIGHT)));
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        let id = node.to_string();
+        self.nodes.retain(|(n, _)| n.to_string() != id);
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        self.scores(key)
+            .into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(node, _)| node)
+    }
+
+    fn candidates(&self, key: &str) -> Vec<&T> {
+        let mut scored = self.scores(key);
+        scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        scored.into_iter().map(|(node, _)| node).collect()
+    }
+}
+
+/// Jump consistent hashing (Lamping & Veach). Each node occupies `weight` consecutive buckets.
+/// Only additions and removals of the most recently added node are minimally disruptive.
+pub struct JumpHash<T> {
+    nodes: Vec<(T, u32)>,
+    buckets: Vec<usize>,
+}
+
+impl<T: ToString + Clone> JumpHash<T> {
+    pub fn new() -> Self {
+        Self {
+            nodes: Vec::new(),
+            buckets: Vec::new(),
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.buckets = self
+            .nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(idx, (_, weight))| std::iter::repeat_n(idx, *weight as usize))
+            .collect();
+    }
+}
+
+impl<T: ToString + Clone> Default for JumpHash<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn jump_consistent_hash(mut key: u64, num_buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < num_buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+impl<T: ToString + Clone + Send> Placement<T> for JumpHash<T> {
+    fn add_node(&mut self, node: &T, weight: u32) {
+        let id = node.to_string();
+        let weight = weight.min(MAX_WEIGHT);
+        // a node added again keeps its place, so the other nodes' buckets stay put
+        match self.nodes.iter_mut().find(|(n, _)| n.to_string() == id) {
+            Some((_, current)) => *current = weight,
+            None => self.nodes.push((node.clone(), weight)),
+        }
+        self.rebuild();
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        let id = node.to_string();
+        self.nodes.retain(|(n, _)| n.to_string() != id);
+        self.rebuild();
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        if self.buckets.is_empty() {
+            return None;
+        }
+        let bucket = jump_consistent_hash(hash(key), self.buckets.len());
+        Some(&self.nodes[self.buckets[bucket]].0)
+    }
+}
+
+/// Maglev hashing: a fixed-size lookup table filled from per-node permutations,
+/// giving near-perfect balance and O(1) lookups at the cost of a rebuild on every change.
+pub struct Maglev<T> {
+    table_size: usize,
+    nodes: Vec<(T, u32)>,
+    table: Vec<usize>,
+}
+
+impl<T: ToString + Clone> Maglev<T> {
+    pub fn new(table_size: usize) -> Self {
+        Self {
+            table_size,
+            nodes: Vec::new(),
+            table: Vec::new(),
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.table.clear();
+        if self.nodes.is_empty() {
+            return;
+        }
+        let m = self.table_size as u64;
+        let permutations: Vec<(u64, u64)> = self
+            .nodes
+            .iter()
+            .map(|(node, _)| {
+                let id = node.to_string();
+                let offset = hash_with_seed(&id, 0xdead) % m;
+                let skip = hash_with_seed(&id, 0xbeef) % (m - 1) + 1;
+                (offset, skip)
+            })
+            .collect();
+        let mut next = vec![0u64; self.nodes.len()];
+        let mut table = vec![usize::MAX; self.table_size];
+        let mut filled = 0;
+        'fill: loop {
+            for (idx, (_, weight)) in self.nodes.iter().enumerate() {
+                let (offset, skip) = permutations[idx];
+                for _ in 0..*weight {
+                    let mut slot = ((offset + next[idx] * skip) % m) as usize;
+                    while table[slot] != usize::MAX {
+                        next[idx] += 1;
+                        slot = ((offset + next[idx] * skip) % m) as usize;
+                    }
+                    table[slot] = idx;
+                    next[idx] += 1;
+                    filled += 1;
+                    if filled == self.table_size {
+                        break 'fill;
+                    }
+                }
+            }
+        }
+        self.table = table;
+    }
+}
+
+impl<T: ToString + Clone + Send> Placement<T> for Maglev<T> {
+    fn add_node(&mut self, node: &T, weight: u32) {
+        let id = node.to_string();
+        let weight = weight.min(MAX_WEIGHT);
+        // a node added again keeps its place, so the other nodes' buckets stay put
+        match self.nodes.iter_mut().find(|(n, _)| n.to_string() == id) {
+            Some((_, current)) => *current = weight,
+            None => self.nodes.push((node.clone(), weight)),
+        }
+        self.rebuild();
+    }
+
+    fn remove_node(&mut self, node: &T) {
+        let id = node.to_string();
+        self.nodes.retain(|(n, _)| n.to_string() != id);
+        self.rebuild();
+    }
+
+    fn get_node(&self, key: &str) -> Option<&T> {
+        if self.table.is_empty() {
+            return None;
+        }
+        let slot = (hash(key) % self.table_size as u64) as usize;
+        Some(&self.nodes[self.table[slot]].0)
+    }
+}
+
+/// How evenly and how stably one strategy spreads a sample keyset.
+#[derive(Debug, Clone)]
+pub struct PlacementStats {
+    pub kind: PlacementKind,
+    /// Most loaded node relative to the mean (1.0 is perfect balance).
+    pub max_over_mean: f64,
+    /// Standard deviation of per-node load relative to the mean.
+    pub relative_stddev: f64,
+    /// Fraction of keys that changed owner when a node was added.
+    pub moved_on_add: f64,
+    /// Fraction of keys that changed owner when a node was removed.
+    pub moved_on_remove: f64,
+}
+
+impl fmt::Display for PlacementStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<12}max/mean {:>6.3}  stddev/mean {:>6.3}  moved on add {:>6.3}  moved on remove {:>6.3}",
+            self.kind.to_string(),
+            self.max_over_mean,
+            self.relative_stddev,
+            self.moved_on_add,
+            self.moved_on_remove
+        )
+    }
+}
+
+fn owners(placement: &dyn Placement<String>, keys: &[String]) -> Vec<String> {
+    keys.iter()
+        .map(|key| placement.get_node(key).cloned().unwrap_or_default())
+        .collect()
+}
+
+fn moved(before: &[String], after: &[String]) -> f64 {
+    let moved = before.iter().zip(after).filter(|(a, b)| a != b).count();
+    moved as f64 / before.len().max(1) as f64
+}
+
+/// Spreads `num_keys` synthetic keys over `num_nodes` equally weighted nodes with every strategy,
+/// then measures how many keys move when one node joins and when the first node leaves.
+pub fn compare(num_nodes: usize, num_keys: usize, virtual_nodes: u32) -> Vec<PlacementStats> {
+    let nodes: Vec<String> = (0..num_nodes).map(|i| format!("node-{}", i)).collect();
+    let keys: Vec<String> = (0..num_keys).map(|i| format!("key:{}", i)).collect();
+
+    PlacementKind::ALL
+        .iter()
+        .map(|kind| {
+            let mut placement = kind.build::<String>(virtual_nodes);
+            for node in &nodes {
+                placement.add_node(node, 1);
+            }
+            let base = owners(placement.as_ref(), &keys);
+
+            let mean = num_keys as f64 / num_nodes.max(1) as f64;
+            let loads: Vec<f64> = nodes
+                .iter()
+                .map(|node| base.iter().filter(|owner| *owner == node).count() as f64)
+                .collect();
+            let max = loads.iter().cloned().fold(0.0, f64::max);
+            let variance =
+                loads.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / loads.len().max(1) as f64;
+
+            let extra = format!("node-{}", num_nodes);
+            placement.add_node(&extra, 1);
+            let moved_on_add = moved(&base, &owners(placement.as_ref(), &keys));
+            placement.remove_node(&extra);
+
+            if let Some(first) = nodes.first() {
+                placement.remove_node(first);
+            }
+            let moved_on_remove = moved(&base, &owners(placement.as_ref(), &keys));
+
+            PlacementStats {
+                kind: *kind,
+                max_over_mean: max / mean,
+                relative_stddev: variance.sqrt() / mean,
+                moved_on_add,
+                moved_on_remove,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> Vec<String> {
+        (0..2000).map(|i| format!("key:{}", i)).collect()
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        for kind in [
+            PlacementKind::Ring,
+            PlacementKind::Rendezvous,
+            PlacementKind::Maglev,
+        ] {
+            let mut placement = kind.build::<String>(50);
+            for i in 0..5 {
+                placement.add_node(&format!("node-{}", i), 1);
+            }
+            let before = owners(placement.as_ref(), &keys());
+            placement.remove_node(&"node-2".to_string());
+            let after = owners(placement.as_ref(), &keys());
+            let needless = before
+                .iter()
+                .zip(&after)
+                .filter(|(b, a)| b != a && *b != "node-2")
+                .count();
+            // Maglev only keeps disruption small, the others keep it minimal
+            let allowed = match kind {
+                PlacementKind::Maglev => keys().len() / 20,
+                _ => 0,
+            };
+            assert!(
+                needless <= allowed,
+                "{} moved {} keys it did not need to",
+                kind,
+                needless
+            );
+        }
+    }
+
+    #[test]
+    fn adding_a_node_twice_keeps_its_share() {
+        for kind in PlacementKind::ALL {
+            let mut placement = kind.build::<String>(50);
+            placement.add_node(&"a".to_string(), 1);
+            placement.add_node(&"b".to_string(), 1);
+            let before = owners(placement.as_ref(), &keys());
+            placement.add_node(&"a".to_string(), 1);
+            let after = owners(placement.as_ref(), &keys());
+            assert!(
+                before == after,
+                "{} moved keys when a node was added again",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn weights_scale_share_of_keys() {
+        for kind in PlacementKind::ALL {
+            let mut placement = kind.build::<String>(100);
+            placement.add_node(&"small".to_string(), 1);
+            placement.add_node(&"large".to_string(), 4);
+            let owners = owners(placement.as_ref(), &keys());
+            let large = owners.iter().filter(|o| *o == "large").count();
+            assert!(
+                large > 2000 * 6 / 10,
+                "{} gave large only {} keys",
+                kind,
+                large
+            );
+        }
+    }
+
+    #[test]
+    fn weights_are_capped() {
+        assert_eq!(
+            ConsistentRing::<String>::new(u32::MAX).virtual_nodes,
+            MAX_VIRTUAL_NODES
+        );
+        let mut ring = ConsistentRing::new(1);
+        ring.add_node(&"huge".to_string(), u32::MAX);
+        assert_eq!(ring.points.len(), MAX_WEIGHT as usize);
+
+        let mut jump = JumpHash::new();
+        jump.add_node(&"huge".to_string(), u32::MAX);
+        assert_eq!(jump.buckets.len(), MAX_WEIGHT as usize);
+    }
+
+    #[test]
+    fn bounded_choice_spills_past_overloaded_owner() {
+        let mut ring = ConsistentRing::new(50);
+        for i in 0..4 {
+            ring.add_node(&format!("node-{}", i), 1);
+        }
+        let candidates = ring.candidates("hot");
+        assert_eq!(candidates.len(), 4);
+        assert_eq!(candidates[0], ring.get_node("hot").unwrap());
+
+        let owner = candidates[0].clone();
+        let load = |node: &String| if *node == owner { 10 } else { 0 };
+        let chosen = bounded_choice(&candidates, load, 10, 4, 1.25).unwrap();
+        assert_eq!(chosen, candidates[1]);
+
+        let idle = |_: &String| 0;
+        let chosen = bounded_choice(&candidates, idle, 0, 4, 1.25).unwrap();
+        assert_eq!(chosen, candidates[0]);
+    }
+
+    #[test]
+    fn hash_tag_extracts_braced_section() {
+        assert_eq!(hash_tag("{user:1}:profile"), "user:1");
+        assert_eq!(hash_tag("cart:{user:1}"), "user:1");
+        assert_eq!(hash_tag("a{b}{c}"), "b");
+        assert_eq!(hash_tag("{}:key"), "{}:key");
+        assert_eq!(hash_tag("no}tag{"), "no}tag{");
+        assert_eq!(hash_tag("plain"), "plain");
+    }
+
+    #[test]
+    fn empty_placement_has_no_owner() {
+        for kind in PlacementKind::ALL {
+            assert!(kind.build::<String>(1).get_node("key").is_none());
+        }
+    }
+}
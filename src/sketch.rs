@@ -0,0 +1,189 @@
+use crate::error::{Error, ErrorCode};
+use crate::placement::hash_with_seed;
+
+/// Bits of the hash that pick a register, giving a standard error of about 0.8%.
+const HLL_PRECISION: u32 = 14;
+const HLL_REGISTERS: usize = 1 << HLL_PRECISION;
+
+/// HyperLogLog cardinality estimator with dense registers, as used by `PFADD` and `PFCOUNT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self {
+            registers: vec![0; HLL_REGISTERS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    /// Adds `item`, returning whether the estimate may have changed.
+    pub fn add(&mut self, item: &str) -> bool {
+        let hash = hash_with_seed(item, 0);
+        let idx = (hash >> (64 - HLL_PRECISION)) as usize;
+        // the guard bit caps the run of zeros for hashes whose remaining bits are all zero
+        let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if rank > self.registers[idx] {
+            self.registers[idx] = rank;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|&rank| 2f64.powi(-(rank as i32)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|&&rank| rank == 0).count();
+        if estimate <= 2.5 * m && zeros > 0 {
+            // linear counting is more accurate while many registers are still empty
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    /// Folds `other` in, so the result counts the union of both. Returns whether any
+    /// register changed.
+    pub fn merge(&mut self, other: &HyperLogLog) -> bool {
+        let mut changed = false;
+        for (mine, theirs) in self.registers.iter_mut().zip(&other.registers) {
+            changed |= *theirs > *mine;
+            *mine = (*mine).max(*theirs);
+        }
+        changed
+    }
+
+    /// Size of the registers in bytes.
+    pub fn size(&self) -> usize {
+        self.registers.len()
+    }
+
+    /// Hex-encoded registers, used to move sketches between partitions and the master.
+    pub fn to_hex(&self) -> String {
+        self.registers
+            .iter()
+            .map(|r| format!("{:02x}", r))
+            .collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, Error> {
+        let invalid = || Error::from_code(ErrorCode::InvalidRequestArg);
+        if hex.len() != HLL_REGISTERS * 2 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let registers = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid())?;
+        Ok(Self { registers })
+    }
+}
+
+/// Items a Bloom filter created by `BF.ADD` is sized for.
+pub const BLOOM_CAPACITY: usize = 1000;
+/// False positive rate a Bloom filter has at `BLOOM_CAPACITY` items.
+pub const BLOOM_ERROR_RATE: f64 = 0.01;
+
+/// Bloom filter answering "seen before?" with no false negatives, as used by `BF.ADD` and
+/// `BF.EXISTS`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self::new(BLOOM_CAPACITY, BLOOM_ERROR_RATE)
+    }
+}
+
+impl BloomFilter {
+    pub fn new(capacity: usize, error_rate: f64) -> Self {
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-(capacity as f64) * error_rate.ln() / (ln2 * ln2)).ceil() as u64;
+        let num_hashes = ((num_bits as f64 / capacity as f64) * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// Bit positions for `item`, derived from two hashes by double hashing.
+    fn positions(&self, item: &str) -> impl Iterator<Item = u64> {
+        let (h1, h2) = (hash_with_seed(item, 0), hash_with_seed(item, 1));
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    /// Adds `item`, returning `false` if it may already have been present.
+    pub fn add(&mut self, item: &str) -> bool {
+        let mut added = false;
+        for pos in self.positions(item).collect::<Vec<_>>() {
+            let (word, mask) = ((pos / 64) as usize, 1u64 << (pos % 64));
+            added |= self.bits[word] & mask == 0;
+            self.bits[word] |= mask;
+        }
+        added
+    }
+
+    pub fn contains(&self, item: &str) -> bool {
+        self.positions(item)
+            .all(|pos| self.bits[(pos / 64) as usize] & (1u64 << (pos % 64)) != 0)
+    }
+
+    /// Size of the bit array in bytes.
+    pub fn size(&self) -> usize {
+        self.bits.len() * std::mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hyperloglog_estimates_and_merges() {
+        let (mut a, mut b) = (HyperLogLog::default(), HyperLogLog::default());
+        for i in 0..10_000 {
+            a.add(&format!("user:{}", i));
+            b.add(&format!("user:{}", i + 5_000));
+        }
+        assert!(!a.add("user:1"));
+        let error = |count: u64, expected: f64| (count as f64 - expected).abs() / expected;
+        assert!(error(a.count(), 10_000.0) < 0.03);
+
+        a.merge(&b);
+        assert!(error(a.count(), 15_000.0) < 0.03);
+        assert_eq!(HyperLogLog::from_hex(&a.to_hex()).unwrap(), a);
+        assert!(HyperLogLog::from_hex("00").is_err());
+    }
+
+    #[test]
+    fn bloom_filter_has_no_false_negatives() {
+        let mut filter = BloomFilter::default();
+        for i in 0..BLOOM_CAPACITY {
+            filter.add(&i.to_string());
+        }
+        assert!((0..BLOOM_CAPACITY).all(|i| filter.contains(&i.to_string())));
+        assert!(!filter.add("1"));
+        let false_positives = (BLOOM_CAPACITY..BLOOM_CAPACITY * 11)
+            .filter(|i| filter.contains(&i.to_string()))
+            .count();
+        assert!(false_positives < BLOOM_CAPACITY * 10 / 50);
+    }
+}
@@ -0,0 +1,1323 @@
+use crate::error::{Error, ErrorCode};
+use crate::glob::glob_match;
+use crate::sketch::{BloomFilter, HyperLogLog};
+use crate::throttle::{RateLimit, ThrottleResult, Throttles};
+use crate::value::{SortedSet, Value};
+use lru::LruCache;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::ops::Bound;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub value: Value,
+    /// Changes on every write. Versions are never reused within a store, even across deletes.
+    pub version: u64,
+    /// When the key was first written. Overwrites keep the original creation time.
+    pub created_at: SystemTime,
+    pub last_access: SystemTime,
+    /// Number of reads served from this entry since the key was created.
+    pub access_count: u64,
+    /// Tags given by the last `SET`, which `INVALIDATE` can delete the key by.
+    pub tags: Vec<String>,
+    /// Soft expiry: from then on the value is still served, but flagged as stale.
+    pub stale_at: Option<Instant>,
+    /// Hard expiry: from then on the key is gone.
+    pub expires_at: Option<Instant>,
+}
+
+impl Entry {
+    fn new(value: Value, version: u64) -> Self {
+        let now = SystemTime::now();
+        Self {
+            value,
+            version,
+            created_at: now,
+            last_access: now,
+            access_count: 0,
+            tags: Vec::new(),
+            stale_at: None,
+            expires_at: None,
+        }
+    }
+
+    pub fn is_stale(&self, now: Instant) -> bool {
+        self.stale_at.is_some_and(|at| at <= now)
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Size of the stored value in bytes.
+    pub fn size(&self) -> usize {
+        self.value.size()
+    }
+}
+
+/// Precondition for a conditional write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    /// Only write if the key does not exist (`NX`).
+    IfAbsent,
+    /// Only write if the key already exists (`XX`).
+    IfPresent,
+    /// Only write if the entry is at this version; version 0 stands for a missing key.
+    IfVersion(u64),
+}
+
+/// A lease on a lock key. Locks live apart from cached entries so they are never evicted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lock {
+    /// Client ID of the holder.
+    pub owner: String,
+    /// Fencing token handed out when the lock was acquired. Tokens only ever grow, so a
+    /// resource can reject writes carrying a token older than the last one it saw.
+    pub token: u64,
+    pub expires_at: Instant,
+}
+
+/// A fill lease on a missing key, handed to the first client that missed it so that only
+/// that client recomputes the value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub token: u64,
+    pub expires_at: Instant,
+}
+
+impl Lock {
+    fn is_held_by(&self, owner: &str, now: Instant) -> bool {
+        self.owner == owner && self.expires_at > now
+    }
+}
+
+/// A change to a key, reported to the master so clients can watch the keyspace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KeyEvent {
+    /// The key was written.
+    Set,
+    /// The key was deleted, or its collection lost its last element.
+    Del,
+    /// The key's time to live ran out.
+    Expired,
+    /// The key was dropped to make room for another one.
+    Evicted,
+}
+
+impl KeyEvent {
+    pub const ALL: [KeyEvent; 4] = [
+        KeyEvent::Set,
+        KeyEvent::Del,
+        KeyEvent::Expired,
+        KeyEvent::Evicted,
+    ];
+}
+
+impl fmt::Display for KeyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeyEvent::Set => "set",
+            KeyEvent::Del => "del",
+            KeyEvent::Expired => "expired",
+            KeyEvent::Evicted => "evicted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for KeyEvent {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyEvent::ALL
+            .into_iter()
+            .find(|event| event.to_string().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::from_code(ErrorCode::InvalidRequestArg))
+    }
+}
+
+/// The key-value store held by a partition. Every method runs to completion on the partition's
+/// request loop, so each one is atomic with respect to other requests.
+pub struct Store {
+    cache: LruCache<String, Entry>,
+    last_version: u64,
+    /// Changes not yet collected with `take_events`.
+    events: Vec<(KeyEvent, String)>,
+    locks: HashMap<String, Lock>,
+    /// Last fencing token handed out. See `next_fencing_token`.
+    last_token: u64,
+    /// Fill leases on missing keys, voided by any write or delete of the key.
+    leases: HashMap<String, Lease>,
+    throttles: Throttles,
+    /// Keys pushed out to make room since the store was created.
+    evictions: u64,
+    /// Entries evicted since the last `take_evicted`, when they are kept for a disk tier.
+    evicted: Option<Vec<(String, Entry)>>,
+    /// Keys carrying each tag. Kept in step with the entries' own tags.
+    tags: HashMap<String, HashSet<String>>,
+    /// Hard expiries, soonest first. Entries removed or given another expiry since are
+    /// skipped when their time comes.
+    expiries: BTreeSet<(Instant, String)>,
+}
+
+impl Store {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self {
+            cache: LruCache::new(capacity),
+            last_version: 0,
+            events: Vec::new(),
+            locks: HashMap::new(),
+            last_token: 0,
+            leases: HashMap::new(),
+            throttles: Throttles::default(),
+            evictions: 0,
+            evicted: None,
+            tags: HashMap::new(),
+            expiries: BTreeSet::new(),
+        }
+    }
+
+    /// Drains the key events recorded since the last call, oldest first.
+    pub fn take_events(&mut self) -> Vec<(KeyEvent, String)> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn record(&mut self, event: KeyEvent, key: &str) {
+        if event != KeyEvent::Evicted {
+            self.leases.remove(key);
+        }
+        self.events.push((event, key.to_string()));
+    }
+
+    /// Adds a new entry, recording the key it pushes out if the store is full.
+    fn insert(&mut self, key: String, entry: Entry) {
+        if let Some((evicted, entry)) = self.cache.push(key.clone(), entry) {
+            if evicted != key {
+                self.evictions += 1;
+                self.untag(&evicted, &entry.tags);
+                self.record(KeyEvent::Evicted, &evicted);
+                if let Some(kept) = &mut self.evicted {
+                    kept.push((evicted, entry));
+                }
+            }
+        }
+    }
+
+    /// Reads an entry, recording the access in its metadata.
+    pub fn get(&mut self, key: &str) -> Option<&Entry> {
+        let now = Instant::now();
+        let entry = self
+            .cache
+            .get_mut(key)
+            .filter(|entry| !entry.is_expired(now))?;
+        entry.last_access = SystemTime::now();
+        entry.access_count += 1;
+        Some(entry)
+    }
+
+    /// Reads an entry without touching its metadata or its LRU position.
+    pub fn peek(&self, key: &str) -> Option<&Entry> {
+        let now = Instant::now();
+        self.cache.peek(key).filter(|entry| !entry.is_expired(now))
+    }
+
+    /// Stores the string `value` under `key`, replacing any kind of value, and returns the
+    /// entry's new version.
+    pub fn set(&mut self, key: String, value: String) -> u64 {
+        self.put(key, Value::Str(value))
+    }
+
+    fn put(&mut self, key: String, value: Value) -> u64 {
+        self.last_version += 1;
+        let version = self.last_version;
+        self.record(KeyEvent::Set, &key);
+        match self.cache.get_mut(&key) {
+            Some(entry) => {
+                entry.value = value;
+                entry.version = version;
+                entry.last_access = SystemTime::now();
+            }
+            None => self.insert(key, Entry::new(value, version)),
+        }
+        version
+    }
+
+    /// Writes only when `condition` holds, returning the new version, or `None` if nothing was stored.
+    pub fn set_if(&mut self, key: String, value: String, condition: SetCondition) -> Option<u64> {
+        let current = self.cache.peek(&key).map(|entry| entry.version);
+        let holds = match condition {
+            SetCondition::IfAbsent => current.is_none(),
+            SetCondition::IfPresent => current.is_some(),
+            SetCondition::IfVersion(version) => current.unwrap_or(0) == version,
+        };
+        holds.then(|| self.set(key, value))
+    }
+
+    /// Stores `value` under `key` and returns the string it replaced.
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>, Error> {
+        let old = match self.cache.peek(&key) {
+            Some(entry) => Some(entry.value.as_str()?.to_string()),
+            None => None,
+        };
+        self.set(key, value);
+        Ok(old)
+    }
+
+    /// Caches `value` loaded from a backend under a missing `key`. Unlike a write this records
+    /// no event, since the key's value has not changed, and leaves leases on the key alone.
+    pub fn fill(&mut self, key: String, value: String) -> u64 {
+        self.last_version += 1;
+        let version = self.last_version;
+        self.insert(key, Entry::new(Value::Str(value), version));
+        version
+    }
+
+    /// Keeps evicted entries around for `take_evicted` instead of dropping them.
+    pub fn keep_evicted(&mut self) {
+        self.evicted.get_or_insert_with(Vec::new);
+    }
+
+    /// Drains the entries evicted since the last call, oldest first, if they are kept.
+    pub fn take_evicted(&mut self) -> Vec<(String, Entry)> {
+        self.evicted
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Puts an evicted entry back under a missing `key` as it was, with its version, tags
+    /// and expiries. Like `fill`, this records no event.
+    pub fn restore(&mut self, key: String, entry: Entry) {
+        for tag in &entry.tags {
+            self.tags
+                .entry(tag.clone())
+                .or_default()
+                .insert(key.clone());
+        }
+        if let Some(at) = entry.expires_at {
+            self.expiries.insert((at, key.clone()));
+        }
+        self.insert(key, entry);
+    }
+
+    pub fn delete(&mut self, key: &str) -> Option<Value> {
+        let entry = self.cache.pop(key)?;
+        self.untag(key, &entry.tags);
+        self.record(KeyEvent::Del, key);
+        Some(entry.value)
+    }
+
+    /// Drops `key` without recording an event, for a write that never took effect because it
+    /// could not be passed on to a backend.
+    pub fn discard(&mut self, key: &str) {
+        if let Some(entry) = self.cache.pop(key) {
+            self.untag(key, &entry.tags);
+        }
+    }
+
+    /// Replaces the tags of `key`, returning `false` if there is no such key.
+    pub fn tag(&mut self, key: &str, tags: Vec<String>) -> bool {
+        let Some(entry) = self.cache.peek_mut(key) else {
+            return false;
+        };
+        let old = std::mem::replace(&mut entry.tags, tags.clone());
+        self.untag(key, &old);
+        for tag in tags {
+            self.tags.entry(tag).or_default().insert(key.to_string());
+        }
+        true
+    }
+
+    fn untag(&mut self, key: &str, tags: &[String]) {
+        for tag in tags {
+            if let Some(keys) = self.tags.get_mut(tag) {
+                keys.remove(key);
+                if keys.is_empty() {
+                    self.tags.remove(tag);
+                }
+            }
+        }
+    }
+
+    /// Sets when `key` turns stale and when it expires, counting from now. `None` clears
+    /// either. Returns `false` if there is no such key. The store's other writes keep both,
+    /// but the `SET` command calls this after every write, so a `SET` without `EX` or `SOFT`
+    /// clears them.
+    pub fn expire(&mut self, key: &str, soft: Option<Duration>, hard: Option<Duration>) -> bool {
+        let now = Instant::now();
+        let Some(entry) = self.cache.peek_mut(key) else {
+            return false;
+        };
+        entry.stale_at = soft.map(|ttl| now + ttl);
+        entry.expires_at = hard.map(|ttl| now + ttl);
+        if let Some(at) = entry.expires_at {
+            self.expiries.insert((at, key.to_string()));
+        }
+        true
+    }
+
+    /// Removes every key past its hard expiry, recording an `expired` event for each, and
+    /// returns how many were removed. Reads already skip such keys, this frees them. Locks
+    /// whose lease ran out are dropped too.
+    pub fn purge_expired(&mut self) -> usize {
+        let now = Instant::now();
+        let mut removed = 0;
+        while let Some((at, key)) = self.expiries.first().filter(|(at, _)| *at <= now).cloned() {
+            self.expiries.pop_first();
+            let due = self
+                .cache
+                .peek(&key)
+                .is_some_and(|entry| entry.expires_at == Some(at));
+            if let Some(entry) = due.then(|| self.cache.pop(&key)).flatten() {
+                self.untag(&key, &entry.tags);
+                self.record(KeyEvent::Expired, &key);
+                removed += 1;
+            }
+        }
+        self.locks.retain(|_, lock| lock.expires_at > now);
+        removed
+    }
+
+    /// Deletes every key carrying `tag`, returning how many were deleted.
+    pub fn invalidate(&mut self, tag: &str) -> usize {
+        let keys = self.tags.remove(tag).unwrap_or_default();
+        for key in &keys {
+            self.delete(key);
+        }
+        keys.len()
+    }
+
+    /// Number of cached keys.
+    pub fn len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cache.is_empty()
+    }
+
+    /// Most keys the store holds before it starts evicting.
+    pub fn capacity(&self) -> usize {
+        self.cache.cap().get()
+    }
+
+    /// Number of keys evicted to make room for others.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Removes every key matching `pattern`, or every key when there is none, returning how
+    /// many were removed. No key events are recorded for flushed keys. Locks and rate limits
+    /// are not cached entries and are kept.
+    pub fn flush(&mut self, pattern: Option<&str>) -> usize {
+        let Some(pattern) = pattern else {
+            let removed = self.cache.len();
+            self.cache.clear();
+            self.tags.clear();
+            self.expiries.clear();
+            return removed;
+        };
+        let keys: Vec<String> = self
+            .cache
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| glob_match(pattern, key))
+            .cloned()
+            .collect();
+        for key in &keys {
+            if let Some(entry) = self.cache.pop(key) {
+                self.untag(key, &entry.tags);
+            }
+        }
+        keys.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.cache.iter().map(|(key, entry)| (key, &entry.value))
+    }
+
+    /// Applies a read-only `op` to the value at `key`, recording the access.
+    /// Returns `None` without calling `op` when the key does not exist.
+    fn read<T>(
+        &mut self,
+        key: &str,
+        op: impl FnOnce(&mut Value) -> Result<T, Error>,
+    ) -> Result<Option<T>, Error> {
+        let Some(entry) = self.cache.get_mut(key) else {
+            return Ok(None);
+        };
+        entry.last_access = SystemTime::now();
+        entry.access_count += 1;
+        op(&mut entry.value).map(Some)
+    }
+
+    /// Applies `op` to the value at `key`, creating it from `empty` first if the key is missing.
+    /// Creating the key counts as a change, as in `modify`.
+    fn upsert<T>(
+        &mut self,
+        key: &str,
+        empty: impl FnOnce() -> Value,
+        op: impl FnOnce(&mut Value) -> Result<(T, bool), Error>,
+    ) -> Result<T, Error> {
+        let created = !self.cache.contains(key);
+        if created {
+            // `modify` assigns the version and records the write
+            self.insert(key.to_string(), Entry::new(empty(), 0));
+        }
+        self.modify(key, |value| {
+            op(value).map(|(result, changed)| (result, changed || created))
+        })
+        .map(|result| result.expect("entry was just created"))
+    }
+
+    /// Applies `op` to the value at `key` if it exists. `op` also returns whether it changed
+    /// the value; only then is the version bumped and the write recorded, so that a no-op
+    /// such as removing an absent member neither aborts transactions watching the key nor
+    /// sends an event. Collections that end up empty are removed.
+    fn modify<T>(
+        &mut self,
+        key: &str,
+        op: impl FnOnce(&mut Value) -> Result<(T, bool), Error>,
+    ) -> Result<Option<T>, Error> {
+        let Some(entry) = self.cache.get_mut(key) else {
+            return Ok(None);
+        };
+        let result = op(&mut entry.value);
+        let changed = matches!(result, Ok((_, true)));
+        if changed {
+            self.last_version += 1;
+            entry.version = self.last_version;
+            entry.last_access = SystemTime::now();
+        }
+        let emptied = entry.value.is_empty_collection();
+        if let Some(entry) = emptied.then(|| self.cache.pop(key)).flatten() {
+            self.untag(key, &entry.tags);
+        }
+        match (changed, emptied) {
+            (true, false) => self.record(KeyEvent::Set, key),
+            (true, true) => self.record(KeyEvent::Del, key),
+            // an unchanged value can only be empty if it was only just created
+            (false, _) => {}
+        }
+        result.map(|(result, _)| Some(result))
+    }
+
+    /// Sets hash fields, returning how many of them are new.
+    pub fn hset(&mut self, key: &str, pairs: Vec<(String, String)>) -> Result<usize, Error> {
+        self.upsert(
+            key,
+            || Value::Hash(HashMap::new()),
+            |value| {
+                let hash = value.as_hash()?;
+                let (mut added, mut changed) = (0, false);
+                for (field, v) in pairs {
+                    let old = hash.insert(field, v.clone());
+                    changed |= old.as_ref() != Some(&v);
+                    added += usize::from(old.is_none());
+                }
+                Ok((added, changed))
+            },
+        )
+    }
+
+    pub fn hget(&mut self, key: &str, field: &str) -> Result<Option<String>, Error> {
+        self.read(key, |value| Ok(value.as_hash()?.get(field).cloned()))
+            .map(Option::flatten)
+    }
+
+    /// Removes hash fields, returning how many existed.
+    pub fn hdel(&mut self, key: &str, fields: &[String]) -> Result<usize, Error> {
+        self.modify(key, |value| {
+            let hash = value.as_hash()?;
+            let removed = fields.iter().filter(|f| hash.remove(*f).is_some()).count();
+            Ok((removed, removed > 0))
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    /// All fields and values of a hash, sorted by field.
+    pub fn hgetall(&mut self, key: &str) -> Result<Vec<(String, String)>, Error> {
+        self.read(key, |value| {
+            let mut pairs: Vec<(String, String)> = value
+                .as_hash()?
+                .iter()
+                .map(|(f, v)| (f.clone(), v.clone()))
+                .collect();
+            pairs.sort();
+            Ok(pairs)
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    /// Pushes values onto the head (`front`) or tail of a list, returning its new length.
+    pub fn push(&mut self, key: &str, values: Vec<String>, front: bool) -> Result<usize, Error> {
+        self.upsert(
+            key,
+            || Value::List(VecDeque::new()),
+            |value| {
+                let list = value.as_list()?;
+                let changed = !values.is_empty();
+                for v in values {
+                    if front {
+                        list.push_front(v);
+                    } else {
+                        list.push_back(v);
+                    }
+                }
+                Ok((list.len(), changed))
+            },
+        )
+    }
+
+    pub fn lpop(&mut self, key: &str) -> Result<Option<String>, Error> {
+        self.modify(key, |value| {
+            let popped = value.as_list()?.pop_front();
+            let changed = popped.is_some();
+            Ok((popped, changed))
+        })
+        .map(Option::flatten)
+    }
+
+    /// Elements `start..=stop` of a list, where negative indexes count from the end.
+    pub fn lrange(&mut self, key: &str, start: i64, stop: i64) -> Result<Vec<String>, Error> {
+        self.read(key, |value| {
+            let list = value.as_list()?;
+            let len = list.len() as i64;
+            let resolve = |idx: i64| if idx < 0 { len + idx } else { idx };
+            let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+            if start > stop {
+                return Ok(Vec::new());
+            }
+            Ok(list
+                .range(start as usize..=stop as usize)
+                .cloned()
+                .collect())
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    /// Adds set members, returning how many were not already present.
+    pub fn sadd(&mut self, key: &str, members: Vec<String>) -> Result<usize, Error> {
+        self.upsert(
+            key,
+            || Value::Set(HashSet::new()),
+            |value| {
+                let set = value.as_set()?;
+                let added = members
+                    .into_iter()
+                    .filter(|m| set.insert(m.clone()))
+                    .count();
+                Ok((added, added > 0))
+            },
+        )
+    }
+
+    /// Removes set members, returning how many were present.
+    pub fn srem(&mut self, key: &str, members: &[String]) -> Result<usize, Error> {
+        self.modify(key, |value| {
+            let set = value.as_set()?;
+            let removed = members.iter().filter(|m| set.remove(*m)).count();
+            Ok((removed, removed > 0))
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    /// All members of a set, sorted.
+    pub fn smembers(&mut self, key: &str) -> Result<Vec<String>, Error> {
+        self.read(key, |value| {
+            let mut members: Vec<String> = value.as_set()?.iter().cloned().collect();
+            members.sort_unstable();
+            Ok(members)
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    pub fn sismember(&mut self, key: &str, member: &str) -> Result<bool, Error> {
+        self.read(key, |value| Ok(value.as_set()?.contains(member)))
+            .map(|found| found.unwrap_or(false))
+    }
+
+    /// Adds or updates sorted set members, returning how many are new.
+    pub fn zadd(&mut self, key: &str, members: Vec<(f64, String)>) -> Result<usize, Error> {
+        self.upsert(
+            key,
+            || Value::ZSet(SortedSet::default()),
+            |value| {
+                let zset = value.as_zset()?;
+                let (mut added, mut changed) = (0, false);
+                for (score, member) in members {
+                    changed |= zset.score(&member) != Some(score);
+                    added += usize::from(zset.insert(member, score));
+                }
+                Ok((added, changed))
+            },
+        )
+    }
+
+    /// Removes sorted set members, returning how many were present.
+    pub fn zrem(&mut self, key: &str, members: &[String]) -> Result<usize, Error> {
+        self.modify(key, |value| {
+            let zset = value.as_zset()?;
+            let removed = members.iter().filter(|m| zset.remove(m)).count();
+            Ok((removed, removed > 0))
+        })
+        .map(Option::unwrap_or_default)
+    }
+
+    pub fn zscore(&mut self, key: &str, member: &str) -> Result<Option<f64>, Error> {
+        self.read(key, |value| Ok(value.as_zset()?.score(member)))
+            .map(Option::flatten)
+    }
+
+    pub fn zrank(&mut self, key: &str, member: &str) -> Result<Option<usize>, Error> {
+        self.read(key, |value| Ok(value.as_zset()?.rank(member)))
+            .map(Option::flatten)
+    }
+
+    pub fn zrange_by_rank(
+        &mut self,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, Error> {
+        self.read(key, |value| Ok(value.as_zset()?.range_by_rank(start, stop)))
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn zrange_by_score(
+        &mut self,
+        key: &str,
+        min: Bound<f64>,
+        max: Bound<f64>,
+    ) -> Result<Vec<(String, f64)>, Error> {
+        self.read(key, |value| Ok(value.as_zset()?.range_by_score(min, max)))
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Adds items to the HyperLogLog at `key`, returning whether it was created or its
+    /// estimate may have changed.
+    pub fn pfadd(&mut self, key: &str, items: &[String]) -> Result<bool, Error> {
+        let created = !self.cache.contains(key);
+        self.upsert(
+            key,
+            || Value::Hll(HyperLogLog::default()),
+            |value| {
+                let hll = value.as_hll()?;
+                let changed = items
+                    .iter()
+                    .fold(false, |changed, item| hll.add(item) | changed);
+                Ok((changed, changed))
+            },
+        )
+        .map(|changed| created || changed)
+    }
+
+    /// The HyperLogLogs at `keys` merged into one, or `None` if none of the keys exist.
+    pub fn pfunion(&mut self, keys: &[&str]) -> Result<Option<HyperLogLog>, Error> {
+        let mut union: Option<HyperLogLog> = None;
+        for key in keys {
+            if let Some(hll) = self.read(key, |value| Ok(value.as_hll()?.clone()))? {
+                union.get_or_insert_with(HyperLogLog::default).merge(&hll);
+            }
+        }
+        Ok(union)
+    }
+
+    /// Merges `hll` into the HyperLogLog at `key`, creating it if needed.
+    pub fn pfmerge(&mut self, key: &str, hll: &HyperLogLog) -> Result<(), Error> {
+        self.upsert(
+            key,
+            || Value::Hll(HyperLogLog::default()),
+            |value| Ok(((), value.as_hll()?.merge(hll))),
+        )
+    }
+
+    /// Adds `item` to the Bloom filter at `key`, returning `false` if it may already be present.
+    pub fn bf_add(&mut self, key: &str, item: &str) -> Result<bool, Error> {
+        self.upsert(
+            key,
+            || Value::Bloom(BloomFilter::default()),
+            |value| {
+                let added = value.as_bloom()?.add(item);
+                Ok((added, added))
+            },
+        )
+    }
+
+    pub fn bf_exists(&mut self, key: &str, item: &str) -> Result<bool, Error> {
+        self.read(key, |value| Ok(value.as_bloom()?.contains(item)))
+            .map(|found| found.unwrap_or(false))
+    }
+
+    /// Acquires the lock `key` for `owner` for `ttl`, returning its fencing token, or `None`
+    /// while another client holds it. A holder acquiring again renews its lease and keeps
+    /// its token.
+    pub fn lock(&mut self, key: &str, owner: &str, ttl: Duration) -> Option<u64> {
+        let now = Instant::now();
+        match self.locks.get_mut(key) {
+            Some(lock) if lock.expires_at > now && lock.owner != owner => None,
+            Some(lock) if lock.expires_at > now => {
+                lock.expires_at = now + ttl;
+                Some(lock.token)
+            }
+            _ => {
+                let lock = Lock {
+                    owner: owner.to_string(),
+                    token: self.next_fencing_token(),
+                    expires_at: now + ttl,
+                };
+                let token = lock.token;
+                self.locks.insert(key.to_string(), lock);
+                Some(token)
+            }
+        }
+    }
+
+    /// Fencing tokens carry the wall-clock time in milliseconds above a 20-bit counter, so
+    /// they keep growing across restarts and owner changes as long as the partitions' clocks
+    /// roughly agree and never step back further than the gap between two acquisitions of
+    /// the same lock. Within one store they grow strictly even if the clock does step back.
+    fn next_fencing_token(&mut self) -> u64 {
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        self.last_token = (self.last_token + 1).max(millis << 20);
+        self.last_token
+    }
+
+    /// Hands out a fill lease on `key`, returning its token, or `None` while another client
+    /// holds a live lease on it. Callers only ask for leases on keys they missed or found stale.
+    pub fn lease(&mut self, key: &str, ttl: Duration) -> Option<u64> {
+        let now = Instant::now();
+        self.leases.retain(|_, lease| lease.expires_at > now);
+        if self.leases.contains_key(key) {
+            return None;
+        }
+        self.last_version += 1;
+        let lease = Lease {
+            token: self.last_version,
+            expires_at: now + ttl,
+        };
+        self.leases.insert(key.to_string(), lease);
+        Some(lease.token)
+    }
+
+    /// Stores `value` under `key` if `token` is the key's live lease, returning the new
+    /// version. Since writes void leases, a value computed before the key last changed is
+    /// rejected. Like `set`, this keeps the key's tags and expiry; `SET … LEASE` then resets
+    /// both from its own options, as any `SET` does.
+    pub fn fill_lease(&mut self, key: String, value: String, token: u64) -> Option<u64> {
+        let now = Instant::now();
+        self.leases
+            .get(&key)
+            .is_some_and(|lease| lease.token == token && lease.expires_at > now)
+            .then(|| self.set(key, value))
+    }
+
+    /// Releases the lock `key` if `owner` holds it.
+    pub fn unlock(&mut self, key: &str, owner: &str) -> Result<(), Error> {
+        match self.locks.get(key) {
+            Some(lock) if lock.is_held_by(owner, Instant::now()) => {
+                self.locks.remove(key);
+                Ok(())
+            }
+            _ => Err(Error::from_code(ErrorCode::NotLockHolder)),
+        }
+    }
+
+    /// Pushes the lease on `key` out to `ttl` from now if `owner` holds it, returning the
+    /// unchanged fencing token.
+    pub fn extend(&mut self, key: &str, owner: &str, ttl: Duration) -> Result<u64, Error> {
+        let now = Instant::now();
+        match self.locks.get_mut(key) {
+            Some(lock) if lock.is_held_by(owner, now) => {
+                lock.expires_at = now + ttl;
+                Ok(lock.token)
+            }
+            _ => Err(Error::from_code(ErrorCode::NotLockHolder)),
+        }
+    }
+
+    /// Counts `quantity` requests against the rate limit on `key`.
+    pub fn throttle(&mut self, key: &str, limit: &RateLimit, quantity: u64) -> ThrottleResult {
+        self.throttles.check(key, limit, quantity, Instant::now())
+    }
+
+    /// Adds `delta` to the integer stored at `key`, treating a missing key as 0.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64, Error> {
+        let current = match self.cache.get(key) {
+            Some(entry) => entry
+                .value
+                .as_str()?
+                .trim()
+                .parse::<i64>()
+                .map_err(|_| Error::from_code(ErrorCode::NotNumeric))?,
+            None => 0,
+        };
+        let updated = current
+            .checked_add(delta)
+            .ok_or_else(|| Error::from_code(ErrorCode::NumericOverflow))?;
+        self.set(key.to_string(), updated.to_string());
+        Ok(updated)
+    }
+
+    /// Adds `delta` to the number stored at `key`, treating a missing key as 0.
+    pub fn incr_by_float(&mut self, key: &str, delta: f64) -> Result<f64, Error> {
+        let current = match self.cache.get(key) {
+            Some(entry) => entry
+                .value
+                .as_str()?
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|value| value.is_finite())
+                .ok_or_else(|| Error::from_code(ErrorCode::NotNumeric))?,
+            None => 0.0,
+        };
+        let updated = current + delta;
+        if !updated.is_finite() {
+            return Err(Error::from_code(ErrorCode::NumericOverflow));
+        }
+        self.set(key.to_string(), updated.to_string());
+        Ok(updated)
+    }
+
+    /// Up to `count` keys that sort after `after` and match `pattern`, in ascending order,
+    /// plus whether more keys remain. Paging by key rather than by position means every key
+    /// that exists for the whole scan is returned exactly once, however the LRU order changes.
+    pub fn scan(
+        &self,
+        after: Option<&str>,
+        pattern: Option<&str>,
+        count: usize,
+    ) -> (Vec<String>, bool) {
+        let mut keys: Vec<&String> = self
+            .cache
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| after.is_none_or(|after| key.as_str() > after))
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .collect();
+        let more = keys.len() > count;
+        if more {
+            keys.select_nth_unstable(count);
+            keys.truncate(count);
+        }
+        keys.sort_unstable();
+        (keys.into_iter().cloned().collect(), more)
+    }
+}
+
+/// Namespace of requests that do not select one.
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+/// The stores of a partition, one per namespace. Each namespace gets a share of the partition's
+/// capacity in proportion to its weight, so a namespace that fills up only evicts its own keys.
+pub struct Namespaces {
+    stores: HashMap<String, Store>,
+}
+
+impl Namespaces {
+    /// Splits `capacity` between the default namespace and `weights`. The default namespace
+    /// has a weight of 1 unless `weights` gives it another. Every namespace holds at least one key.
+    pub fn new(capacity: NonZeroUsize, weights: &[(String, u32)]) -> Self {
+        let mut weights: HashMap<&str, u32> = weights
+            .iter()
+            .map(|(name, weight)| (name.as_str(), *weight))
+            .collect();
+        weights.entry(DEFAULT_NAMESPACE).or_insert(1);
+        let total: u64 = weights.values().map(|&weight| weight as u64).sum();
+        let stores = weights
+            .into_iter()
+            .map(|(name, weight)| {
+                let share = capacity.get() as u64 * weight as u64 / total;
+                let share = NonZeroUsize::new(share as usize).unwrap_or(NonZeroUsize::MIN);
+                (name.to_string(), Store::new(share))
+            })
+            .collect();
+        Self { stores }
+    }
+
+    /// The store of `namespace`, or of the default namespace for `None`.
+    pub fn get_mut(&mut self, namespace: Option<&str>) -> Result<&mut Store, Error> {
+        self.stores
+            .get_mut(namespace.unwrap_or(DEFAULT_NAMESPACE))
+            .ok_or_else(|| Error::from_code(ErrorCode::UnknownNamespace))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&str, &mut Store)> {
+        self.stores
+            .iter_mut()
+            .map(|(name, store)| (name.as_str(), store))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> Store {
+        Store::new(NonZeroUsize::new(16).unwrap())
+    }
+
+    #[test]
+    fn counters_start_at_zero_and_reject_bad_values() {
+        let mut store = store();
+        assert_eq!(store.incr_by("hits", 1).unwrap(), 1);
+        assert_eq!(store.incr_by("hits", -3).unwrap(), -2);
+        assert_eq!(
+            store.get("hits").unwrap().value,
+            Value::Str("-2".to_string())
+        );
+
+        store.set("name".to_string(), "Fjoni".to_string());
+        assert_eq!(
+            store.incr_by("name", 1).unwrap_err().code,
+            ErrorCode::NotNumeric
+        );
+
+        store.set("max".to_string(), i64::MAX.to_string());
+        assert_eq!(
+            store.incr_by("max", 1).unwrap_err().code,
+            ErrorCode::NumericOverflow
+        );
+        assert_eq!(
+            store.get("max").unwrap().value,
+            Value::Str(i64::MAX.to_string())
+        );
+
+        assert_eq!(store.incr_by_float("price", 1.5).unwrap(), 1.5);
+        assert_eq!(store.incr_by_float("price", 1.5).unwrap(), 3.0);
+        assert_eq!(
+            store.get("price").unwrap().value,
+            Value::Str("3".to_string())
+        );
+    }
+
+    #[test]
+    fn conditional_writes_check_presence_and_version() {
+        let mut store = store();
+        let key = || "k".to_string();
+        assert!(store
+            .set_if(key(), "a".to_string(), SetCondition::IfPresent)
+            .is_none());
+        let v1 = store
+            .set_if(key(), "a".to_string(), SetCondition::IfAbsent)
+            .unwrap();
+        assert!(store
+            .set_if(key(), "b".to_string(), SetCondition::IfAbsent)
+            .is_none());
+        assert!(store
+            .set_if(key(), "b".to_string(), SetCondition::IfVersion(v1 + 1))
+            .is_none());
+        let v2 = store
+            .set_if(key(), "b".to_string(), SetCondition::IfVersion(v1))
+            .unwrap();
+        assert!(v2 > v1);
+        assert_eq!(
+            store.get_set(key(), "c".to_string()).unwrap(),
+            Some("b".to_string())
+        );
+        assert!(store.get("k").unwrap().version > v2);
+
+        store.delete("k");
+        assert!(store
+            .set_if(key(), "d".to_string(), SetCondition::IfVersion(0))
+            .is_some());
+    }
+
+    #[test]
+    fn metadata_tracks_reads_and_survives_overwrites() {
+        let mut store = store();
+        store.set("k".to_string(), "abc".to_string());
+        let created_at = store.peek("k").unwrap().created_at;
+        store.get("k");
+        store.get("k");
+        store.set("k".to_string(), "abcdef".to_string());
+
+        let entry = store.peek("k").unwrap();
+        assert_eq!(entry.access_count, 2);
+        assert_eq!(entry.created_at, created_at);
+        assert_eq!(entry.size(), 6);
+        assert!(entry.last_access >= created_at);
+    }
+
+    #[test]
+    fn collections_are_type_checked_and_removed_when_empty() {
+        let mut store = store();
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            store
+                .hset("h", vec![("a".to_string(), "1".to_string())])
+                .unwrap(),
+            1
+        );
+        assert_eq!(store.hget("h", "a").unwrap(), Some("1".to_string()));
+        assert_eq!(
+            store.push("l", strings(&["a", "b", "c"]), false).unwrap(),
+            3
+        );
+        assert_eq!(store.push("l", strings(&["z"]), true).unwrap(), 4);
+        assert_eq!(store.lrange("l", 1, -2).unwrap(), strings(&["a", "b"]));
+        assert_eq!(store.lrange("l", 5, 10).unwrap(), Vec::<String>::new());
+        assert_eq!(store.sadd("s", strings(&["x", "y", "x"])).unwrap(), 2);
+        assert!(store.sismember("s", "y").unwrap());
+
+        assert_eq!(store.hget("s", "a").unwrap_err().code, ErrorCode::WrongType);
+        assert_eq!(
+            store.incr_by("l", 1).unwrap_err().code,
+            ErrorCode::WrongType
+        );
+        assert_eq!(
+            store.sadd("h", strings(&["x"])).unwrap_err().code,
+            ErrorCode::WrongType
+        );
+
+        assert_eq!(store.srem("s", &strings(&["x", "y", "q"])).unwrap(), 2);
+        assert!(store.peek("s").is_none());
+        assert_eq!(store.hdel("h", &strings(&["a"])).unwrap(), 1);
+        assert!(store.peek("h").is_none());
+    }
+
+    #[test]
+    fn scan_pages_in_key_order() {
+        let mut store = store();
+        for key in ["d", "a", "c", "b"] {
+            store.set(key.to_string(), key.to_string());
+        }
+        let (page, more) = store.scan(None, None, 3);
+        assert_eq!(page, vec!["a", "b", "c"]);
+        assert!(more);
+        let (page, more) = store.scan(Some("c"), None, 3);
+        assert_eq!(page, vec!["d"]);
+        assert!(!more);
+        let (page, _) = store.scan(None, Some("[bd]"), 10);
+        assert_eq!(page, vec!["b", "d"]);
+    }
+
+    #[test]
+    fn writes_that_change_nothing_keep_the_version() {
+        let mut store = Store::new(NonZeroUsize::new(4).unwrap());
+        store.sadd("s", vec!["x".to_string()]).unwrap();
+        store
+            .hset("h", vec![("f".to_string(), "v".to_string())])
+            .unwrap();
+        store.zadd("z", vec![(1.0, "m".to_string())]).unwrap();
+        store.take_events();
+        let versions = |store: &Store| ["s", "h", "z"].map(|key| store.peek(key).unwrap().version);
+        let before = versions(&store);
+
+        assert_eq!(store.srem("s", &["absent".to_string()]).unwrap(), 0);
+        assert_eq!(store.sadd("s", vec!["x".to_string()]).unwrap(), 0);
+        assert_eq!(store.hdel("h", &["missing".to_string()]).unwrap(), 0);
+        store
+            .hset("h", vec![("f".to_string(), "v".to_string())])
+            .unwrap();
+        assert_eq!(store.zrem("z", &["absent".to_string()]).unwrap(), 0);
+        store.zadd("z", vec![(1.0, "m".to_string())]).unwrap();
+        assert_eq!(versions(&store), before);
+        assert!(store.take_events().is_empty());
+
+        // a new score is a change even though no member was added
+        assert_eq!(store.zadd("z", vec![(2.0, "m".to_string())]).unwrap(), 0);
+        assert!(store.peek("z").unwrap().version > before[2]);
+    }
+
+    #[test]
+    fn records_key_events_including_evictions() {
+        let mut store = Store::new(NonZeroUsize::new(2).unwrap());
+        store.set("a".to_string(), "1".to_string());
+        store.sadd("b", vec!["x".to_string()]).unwrap();
+        store.srem("b", &["x".to_string()]).unwrap();
+        store.delete("a");
+        store.delete("missing");
+        let key = |event, key: &str| (event, key.to_string());
+        assert_eq!(
+            store.take_events(),
+            vec![
+                key(KeyEvent::Set, "a"),
+                key(KeyEvent::Set, "b"),
+                key(KeyEvent::Del, "b"),
+                key(KeyEvent::Del, "a"),
+            ]
+        );
+
+        for k in ["c", "d", "e"] {
+            store.set(k.to_string(), "v".to_string());
+        }
+        assert!(store.take_events().contains(&key(KeyEvent::Evicted, "c")));
+        assert!(store.take_events().is_empty());
+    }
+
+    #[test]
+    fn locks_hand_out_growing_fencing_tokens() {
+        let mut store = store();
+        let lease = Duration::from_secs(30);
+        let first = store.lock("job", "a", lease).unwrap();
+        assert_eq!(store.lock("job", "b", lease), None);
+        assert_eq!(store.lock("job", "a", lease), Some(first));
+        assert_eq!(store.extend("job", "a", lease).unwrap(), first);
+        assert_eq!(
+            store.unlock("job", "b").unwrap_err().code,
+            ErrorCode::NotLockHolder
+        );
+        store.unlock("job", "a").unwrap();
+
+        let second = store.lock("job", "b", Duration::ZERO).unwrap();
+        assert!(second > first);
+        // a zero lease has already run out
+        assert!(store.extend("job", "b", lease).is_err());
+        assert!(store.lock("job", "a", lease).unwrap() > second);
+
+        // a restarted partition starts from an empty store but keeps handing out larger tokens
+        let third = store.lock("other", "a", lease).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(self::store().lock("job", "c", lease).unwrap() > third);
+    }
+
+    #[test]
+    fn flush_removes_matching_keys() {
+        let mut store = store();
+        for key in ["user:1", "user:2", "session:1"] {
+            store.set(key.to_string(), "v".to_string());
+        }
+        assert_eq!(store.flush(Some("user:*")), 2);
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.flush(None), 1);
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn leases_go_to_the_first_misser_and_are_voided_by_writes() {
+        let mut store = store();
+        let ttl = Duration::from_secs(10);
+        let token = store.lease("hot", ttl).unwrap();
+        assert_eq!(store.lease("hot", ttl), None);
+        assert_eq!(
+            store.fill_lease("hot".to_string(), "v".to_string(), token + 1),
+            None
+        );
+        assert!(store
+            .fill_lease("hot".to_string(), "v".to_string(), token)
+            .is_some());
+        // a filled lease is used up
+        assert_eq!(
+            store.fill_lease("hot".to_string(), "w".to_string(), token),
+            None
+        );
+
+        store.delete("hot");
+        let token = store.lease("hot", ttl).unwrap();
+        store.set("hot".to_string(), "fresh".to_string());
+        assert_eq!(
+            store.fill_lease("hot".to_string(), "old".to_string(), token),
+            None
+        );
+        assert_eq!(store.peek("hot").unwrap().value.as_str().unwrap(), "fresh");
+    }
+
+    #[test]
+    fn soft_expired_keys_turn_stale_and_hard_expired_keys_go() {
+        let mut store = store();
+        let hour = Duration::from_secs(3600);
+        store.set("stale".to_string(), "v".to_string());
+        store.expire("stale", Some(Duration::ZERO), Some(hour));
+        store.set("gone".to_string(), "v".to_string());
+        store.expire("gone", None, Some(Duration::ZERO));
+
+        assert!(store.get("stale").unwrap().is_stale(Instant::now()));
+        assert!(store.get("gone").is_none());
+        assert_eq!(store.purge_expired(), 1);
+        assert_eq!(
+            store.take_events().last(),
+            Some(&(KeyEvent::Expired, "gone".to_string()))
+        );
+
+        // a later expiry replaces the earlier one
+        store.expire("stale", None, Some(Duration::ZERO));
+        store.expire("stale", None, Some(hour));
+        assert_eq!(store.purge_expired(), 0);
+        assert!(!store.peek("stale").unwrap().is_stale(Instant::now()));
+    }
+
+    #[test]
+    fn kept_evictions_can_be_restored_as_they_were() {
+        let mut store = Store::new(NonZeroUsize::new(1).unwrap());
+        store.keep_evicted();
+        let version = store.set("a".to_string(), "1".to_string());
+        store.tag("a", vec!["user:1".to_string()]);
+        store.set("b".to_string(), "2".to_string());
+
+        let mut evicted = store.take_evicted();
+        assert!(store.take_evicted().is_empty());
+        let (key, entry) = evicted.pop().unwrap();
+        assert_eq!(key, "a");
+        store.take_events();
+        store.restore(key, entry);
+        assert!(!store
+            .take_events()
+            .contains(&(KeyEvent::Set, "a".to_string())));
+        assert_eq!(store.peek("a").unwrap().version, version);
+        assert_eq!(store.take_evicted()[0].0, "b");
+        assert_eq!(store.invalidate("user:1"), 1);
+    }
+
+    #[test]
+    fn invalidate_deletes_tagged_keys() {
+        let mut store = Store::new(NonZeroUsize::new(3).unwrap());
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect();
+        for (key, key_tags) in [
+            ("page:1", ["user:1", "post:1"]),
+            ("page:2", ["user:2", "post:1"]),
+        ] {
+            store.set(key.to_string(), "html".to_string());
+            assert!(store.tag(key, tags(&key_tags)));
+        }
+        // a later SET without the tag no longer depends on it
+        store.set("page:3".to_string(), "html".to_string());
+        store.tag("page:3", tags(&["user:1"]));
+        store.tag("page:3", Vec::new());
+
+        assert_eq!(store.invalidate("user:1"), 1);
+        assert!(store.peek("page:1").is_none());
+        assert_eq!(store.invalidate("post:1"), 1);
+        assert_eq!(store.len(), 1);
+
+        // evicted keys leave the index
+        store.set("page:4".to_string(), "html".to_string());
+        store.tag("page:4", tags(&["user:4"]));
+        for key in ["a", "b", "c"] {
+            store.set(key.to_string(), "v".to_string());
+        }
+        assert_eq!(store.invalidate("user:4"), 0);
+        assert!(store.tags.is_empty());
+    }
+
+    #[test]
+    fn namespaces_split_capacity_and_evict_separately() {
+        let weights = [("team-a".to_string(), 2), ("team-b".to_string(), 1)];
+        let mut namespaces = Namespaces::new(NonZeroUsize::new(8).unwrap(), &weights);
+        let team_a = namespaces.get_mut(Some("team-a")).unwrap();
+        assert_eq!(team_a.capacity(), 4);
+        for i in 0..6 {
+            team_a.set(format!("key:{}", i), "v".to_string());
+        }
+        assert_eq!(team_a.evictions(), 2);
+
+        let team_b = namespaces.get_mut(Some("team-b")).unwrap();
+        assert_eq!(team_b.capacity(), 2);
+        assert!(team_b.get("key:5").is_none());
+        team_b.set("key:5".to_string(), "b".to_string());
+        assert_eq!(team_b.evictions(), 0);
+
+        assert_eq!(namespaces.get_mut(None).unwrap().capacity(), 2);
+        assert_eq!(
+            namespaces.get_mut(Some("team-c")).err().unwrap().code,
+            ErrorCode::UnknownNamespace
+        );
+    }
+}
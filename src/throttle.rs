@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A rate limit as given to `THROTTLE`: `count_per_period` requests per `period`, with bursts
+/// of up to `max_burst` requests on top of the steady rate. Counts must fit in a `u32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub max_burst: u64,
+    pub count_per_period: u64,
+    pub period: Duration,
+}
+
+impl RateLimit {
+    /// Time between requests at the steady rate.
+    fn emission_interval(&self) -> Duration {
+        (self.period / self.count_per_period as u32).max(Duration::from_nanos(1))
+    }
+
+    /// How far ahead of the steady rate a key may run before it is throttled.
+    fn tolerance(&self) -> Duration {
+        self.emission_interval()
+            .saturating_mul((self.max_burst + 1) as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThrottleResult {
+    pub allowed: bool,
+    /// Requests a key with no recent traffic may make at once.
+    pub limit: u64,
+    pub remaining: u64,
+    /// When the denied request could succeed, or `None` if it never can because it asks
+    /// for more than the limit.
+    pub retry_after: Option<Duration>,
+    /// When the key is back to its full limit.
+    pub reset_after: Duration,
+}
+
+/// Rate limiter state using the generic cell rate algorithm (GCRA). Each key only keeps its
+/// theoretical arrival time: when the next request would be due if all earlier ones had
+/// arrived at the steady rate.
+#[derive(Debug, Default)]
+pub struct Throttles {
+    arrivals: HashMap<String, Instant>,
+    /// Size at which keys that are back to their full limit get dropped.
+    purge_at: usize,
+}
+
+impl Throttles {
+    /// Counts `quantity` requests against `key`. Denied requests are not counted, and a
+    /// quantity of 0 only reports the key's state.
+    pub fn check(
+        &mut self,
+        key: &str,
+        limit: &RateLimit,
+        quantity: u64,
+        now: Instant,
+    ) -> ThrottleResult {
+        let interval = limit.emission_interval();
+        let tolerance = limit.tolerance();
+        let increment = interval.saturating_mul(quantity as u32);
+        // how far the key's arrival time runs ahead of now
+        let backlog = self.arrivals.get(key).map_or(Duration::ZERO, |arrival| {
+            arrival.saturating_duration_since(now)
+        });
+        let ahead = backlog.saturating_add(increment);
+
+        let allowed = ahead <= tolerance;
+        let (retry_after, reset_after) = if allowed {
+            (None, ahead)
+        } else if increment > tolerance {
+            (None, backlog)
+        } else {
+            (Some(ahead - tolerance), backlog)
+        };
+        if let Some(next_arrival) = now.checked_add(ahead).filter(|_| allowed) {
+            self.arrivals.insert(key.to_string(), next_arrival);
+            self.purge(now);
+        }
+        ThrottleResult {
+            allowed,
+            limit: limit.max_burst + 1,
+            remaining: (tolerance.saturating_sub(reset_after).as_nanos() / interval.as_nanos())
+                as u64,
+            retry_after,
+            reset_after,
+        }
+    }
+
+    /// Drops keys whose arrival time has passed once the map has doubled since the last
+    /// purge, so idle keys do not pile up.
+    fn purge(&mut self, now: Instant) {
+        if self.arrivals.len() < self.purge_at {
+            return;
+        }
+        self.arrivals.retain(|_, arrival| *arrival > now);
+        self.purge_at = (self.arrivals.len() * 2).max(64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_bursts_then_the_steady_rate() {
+        // 10 per second with bursts of 4 more
+        let limit = RateLimit {
+            max_burst: 4,
+            count_per_period: 10,
+            period: Duration::from_secs(1),
+        };
+        let mut throttles = Throttles::default();
+        let start = Instant::now();
+
+        for remaining in (0..5).rev() {
+            let result = throttles.check("api", &limit, 1, start);
+            assert!(result.allowed);
+            assert_eq!((result.limit, result.remaining), (5, remaining));
+        }
+        let denied = throttles.check("api", &limit, 1, start);
+        assert!(!denied.allowed);
+        assert_eq!(denied.retry_after, Some(Duration::from_millis(100)));
+        assert_eq!(denied.reset_after, Duration::from_millis(500));
+
+        let later = start + Duration::from_millis(100);
+        assert!(throttles.check("api", &limit, 1, later).allowed);
+        assert!(!throttles.check("api", &limit, 1, later).allowed);
+        assert_eq!(throttles.check("api", &limit, 6, later).retry_after, None);
+        assert_eq!(throttles.check("other", &limit, 0, later).remaining, 5);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::error::{Error, ErrorCode};
+use crate::sketch::{BloomFilter, HyperLogLog};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::ops::Bound;
+
+/// What a key holds. Commands only work on the kind of value they were written for and
+/// answer `WrongType` otherwise.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Str(String),
+    Hash(HashMap<String, String>),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    ZSet(SortedSet),
+    Hll(HyperLogLog),
+    Bloom(BloomFilter),
+}
+
+/// A score that orders totally, so it can key the sorted set index.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, then by member. Lookups by member go through `scores`,
+/// range queries through the ordered `index`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SortedSet {
+    scores: HashMap<String, Score>,
+    index: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    /// Adds `member` or updates its score, returning whether it is new.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let old = self.scores.insert(member.clone(), Score(score));
+        if let Some(old) = old {
+            self.index.remove(&(old, member.clone()));
+        }
+        self.index.insert((Score(score), member));
+        old.is_none()
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.index.remove(&(score, member.to_string())),
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).map(|score| score.0)
+    }
+
+    /// Zero-based position of `member` in ascending score order.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        Some(self.index.range(..(score, member.to_string())).count())
+    }
+
+    /// Members at ranks `start..=stop`, where negative ranks count from the highest score.
+    pub fn range_by_rank(&self, start: i64, stop: i64) -> Vec<(String, f64)> {
+        let len = self.len() as i64;
+        let resolve = |idx: i64| if idx < 0 { len + idx } else { idx };
+        let (start, stop) = (resolve(start).max(0), resolve(stop).min(len - 1));
+        if start > stop {
+            return Vec::new();
+        }
+        self.index
+            .iter()
+            .skip(start as usize)
+            .take((stop - start + 1) as usize)
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
+    }
+
+    /// Members whose score lies within the given bounds, in ascending order.
+    pub fn range_by_score(&self, min: Bound<f64>, max: Bound<f64>) -> Vec<(String, f64)> {
+        let below_max = |score: f64| match max {
+            Bound::Included(max) => score <= max,
+            Bound::Excluded(max) => score < max,
+            Bound::Unbounded => true,
+        };
+        let start = match min {
+            Bound::Included(min) | Bound::Excluded(min) => {
+                Bound::Included((Score(min), String::new()))
+            }
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        self.index
+            .range((start, Bound::Unbounded))
+            .filter(|(score, _)| !matches!(min, Bound::Excluded(min) if score.0 == min))
+            .take_while(|(score, _)| below_max(score.0))
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, f64)> {
+        self.index.iter().map(|(score, member)| (member, score.0))
+    }
+}
+
+pub fn wrong_type() -> Error {
+    Error::from_code(ErrorCode::WrongType)
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Str(_) => "string",
+            Value::Hash(_) => "hash",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::ZSet(_) => "zset",
+            Value::Hll(_) => "hyperloglog",
+            Value::Bloom(_) => "bloom",
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, Error> {
+        match self {
+            Value::Str(s) => Ok(s),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    pub fn as_hash(&mut self) -> Result<&mut HashMap<String, String>, Error> {
+        match self {
+            Value::Hash(hash) => Ok(hash),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    pub fn as_list(&mut self) -> Result<&mut VecDeque<String>, Error> {
+        match self {
+            Value::List(list) => Ok(list),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    pub fn as_set(&mut self) -> Result<&mut HashSet<String>, Error> {
+        match self {
+            Value::Set(set) => Ok(set),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    pub fn as_zset(&mut self) -> Result<&mut SortedSet, Error> {
+        match self {
+            Value::ZSet(zset) => Ok(zset),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    pub fn as_hll(&mut self) -> Result<&mut HyperLogLog, Error> {
+        match self {
+            Value::Hll(hll) => Ok(hll),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    pub fn as_bloom(&mut self) -> Result<&mut BloomFilter, Error> {
+        match self {
+            Value::Bloom(filter) => Ok(filter),
+            _ => Err(wrong_type()),
+        }
+    }
+
+    /// Collections that lose their last element are removed, like in Redis.
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::Str(_) => false,
+            Value::Hash(hash) => hash.is_empty(),
+            Value::List(list) => list.is_empty(),
+            Value::Set(set) => set.is_empty(),
+            Value::ZSet(zset) => zset.is_empty(),
+            Value::Hll(_) | Value::Bloom(_) => false,
+        }
+    }
+
+    /// Approximate payload size in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Value::Str(s) => s.len(),
+            Value::Hash(hash) => hash.iter().map(|(f, v)| f.len() + v.len()).sum(),
+            Value::List(list) => list.iter().map(String::len).sum(),
+            Value::Set(set) => set.iter().map(String::len).sum(),
+            Value::ZSet(zset) => zset
+                .iter()
+                .map(|(member, _)| member.len() + std::mem::size_of::<f64>())
+                .sum(),
+            Value::Hll(hll) => hll.size(),
+            Value::Bloom(filter) => filter.size(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) => write!(f, "{}", s),
+            Value::Hash(hash) => {
+                let mut fields: Vec<_> = hash.iter().collect();
+                fields.sort();
+                let fields: Vec<String> = fields
+                    .into_iter()
+                    .map(|(field, value)| format!("{}: {}", field, value))
+                    .collect();
+                write!(f, "{{{}}}", fields.join(", "))
+            }
+            Value::List(list) => {
+                write!(f, "[{}]", Vec::from(list.clone()).join(", "))
+            }
+            Value::Set(set) => {
+                let mut members: Vec<&str> = set.iter().map(String::as_str).collect();
+                members.sort_unstable();
+                write!(f, "({})", members.join(", "))
+            }
+            Value::ZSet(zset) => {
+                let members: Vec<String> = zset
+                    .iter()
+                    .map(|(member, score)| format!("{}: {}", member, score))
+                    .collect();
+                write!(f, "<{}>", members.join(", "))
+            }
+            Value::Hll(hll) => write!(f, "hyperloglog(~{})", hll.count()),
+            Value::Bloom(filter) => write!(f, "bloom({} bytes)", filter.size()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaderboard() -> SortedSet {
+        let mut zset = SortedSet::default();
+        for (member, score) in [("ann", 30.0), ("bob", 10.0), ("cid", 20.0), ("dan", 20.0)] {
+            zset.insert(member.to_string(), score);
+        }
+        zset
+    }
+
+    fn members(range: Vec<(String, f64)>) -> Vec<String> {
+        range.into_iter().map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn sorted_set_orders_by_score_then_member() {
+        let mut zset = leaderboard();
+        assert_eq!(
+            members(zset.range_by_rank(0, -1)),
+            ["bob", "cid", "dan", "ann"]
+        );
+        assert_eq!(zset.rank("dan"), Some(2));
+
+        assert!(!zset.insert("bob".to_string(), 40.0));
+        assert_eq!(zset.rank("bob"), Some(3));
+        assert_eq!(members(zset.range_by_rank(-2, -1)), ["ann", "bob"]);
+        assert!(zset.remove("ann"));
+        assert_eq!(zset.score("ann"), None);
+        assert_eq!(zset.len(), 3);
+    }
+
+    #[test]
+    fn sorted_set_ranges_by_score() {
+        let zset = leaderboard();
+        let range = |min, max| members(zset.range_by_score(min, max));
+        assert_eq!(
+            range(Bound::Included(20.0), Bound::Included(30.0)),
+            ["cid", "dan", "ann"]
+        );
+        assert_eq!(range(Bound::Excluded(20.0), Bound::Unbounded), ["ann"]);
+        assert_eq!(range(Bound::Unbounded, Bound::Excluded(20.0)), ["bob"]);
+    }
+}